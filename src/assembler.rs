@@ -1,31 +1,438 @@
+use crate::common::{FileId, Loc};
+use crate::debug_info;
+use crate::diagnostic::Diagnostic;
+use crate::insts::AbstructInstruction;
+use crate::listing;
+use crate::loader::{self, FsLoader, SourceLoader};
+use crate::macros::{Includer, LineSink, MacroExpander};
+use crate::nes_header::{NesHeader, RomLocation};
 use crate::parser::Parser;
-use crate::tokenizer;
-use log::debug;
-use std::fs;
+use crate::symbol_table::SymbolKind;
 use std::fs::File;
+use std::io;
 use std::io::Write;
-use std::io::{BufRead, BufReader};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-pub struct Assembler {}
+pub const DEFAULT_MAX_ERRORS: usize = 20;
+
+/// Settings for `assemble_source`.
+pub struct Options {
+    /// Name of the source in diagnostics. `.incbin` paths are relative to
+    /// its directory.
+    pub file_name: String,
+    /// Return only the first this many errors in the source. 0 means no
+    /// limit.
+    pub max_errors: usize,
+    /// Symbols defined before the source is read, like `-D NAME=VALUE`.
+    pub defines: Vec<(String, u16)>,
+    /// Generate `Output::listing`.
+    pub listing: bool,
+    /// Generate `Output::debug_info`.
+    pub debug_info: bool,
+    /// Generate `Output::ram_usage`.
+    pub ram_usage: bool,
+    /// Directories searched, in order, for files not found next to the source.
+    pub search_paths: Vec<PathBuf>,
+    /// Reads the files the source refers to.
+    pub loader: Box<dyn SourceLoader>,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            file_name: "<input>".to_string(),
+            max_errors: DEFAULT_MAX_ERRORS,
+            defines: vec![],
+            listing: false,
+            debug_info: false,
+            ram_usage: false,
+            search_paths: vec![],
+            loader: Box::new(FsLoader),
+        }
+    }
+}
+
+/// How the assembled ROM is written out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// iNES image with the 16-byte header.
+    Nes,
+    /// PRG banks followed by CHR banks, without a header.
+    Raw,
+}
+impl OutputFormat {
+    /// Conventional file extension for the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Nes => "nes",
+            OutputFormat::Raw => "bin",
+        }
+    }
+}
+impl FromStr for OutputFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nes" | "ines" => Ok(OutputFormat::Nes),
+            "raw" | "bin" => Ok(OutputFormat::Raw),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A symbol and the value it was assigned.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub bank: u8,
+    pub address: u16,
+    /// Offset into the PRG ROM data (after the header) when the symbol is a
+    /// label in a PRG bank at $8000-$FFFF.
+    pub prg_offset: Option<usize>,
+    /// Comment on the line that defines the label.
+    pub comment: Option<String>,
+    /// How many expressions use the symbol.
+    pub references: usize,
+    /// Whether the symbol is only visible in the scope that defines it.
+    pub local: bool,
+}
+
+/// The result of a successful assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// The iNES image: header, PRG banks, then CHR banks.
+    pub rom: Vec<u8>,
+    /// Every label, sorted by name.
+    pub symbols: Vec<Symbol>,
+    /// The assembly listing, if `Options::listing` was set.
+    pub listing: Option<String>,
+    /// ld65-style `.dbg` debug info, if `Options::debug_info` was set. It
+    /// names the ROM after `Options::file_name` with a `.nes` extension.
+    pub debug_info: Option<String>,
+    /// How much zero page and work RAM `.rs` and `.ds` reserved, if
+    /// `Options::ram_usage` was set.
+    pub ram_usage: Option<String>,
+    /// Diagnostics that did not stop the assembly.
+    pub warnings: Vec<Diagnostic>,
+}
+impl Output {
+    /// The bytes to write out for `format`.
+    pub fn image(&self, format: OutputFormat) -> &[u8] {
+        match format {
+            OutputFormat::Nes => &self.rom,
+            OutputFormat::Raw => &self.rom[NesHeader::SIZE..],
+        }
+    }
+}
+
+/// Assembles `source` into a ROM image in memory. Nothing is read from or
+/// written to the disk except through `options.loader`.
+pub fn assemble_source(source: &str, options: &Options) -> Result<Output, Vec<Diagnostic>> {
+    let max_errors = if options.max_errors == 0 {
+        usize::MAX
+    } else {
+        options.max_errors
+    };
+    let mut files = SourceFiles::default();
+    let file = files.add(options.file_name.clone(), source.to_string(), None);
+    let mut parser = Parser::new();
+    parser.set_base_path(&options.file_name);
+    parser.set_search_paths(options.search_paths.clone());
+    for (name, value) in &options.defines {
+        parser.define(name.clone(), *value);
+    }
+    // Macros and `.include`s are expanded as the parser reads the lines, so
+    // that conditionals can skip them, once for every pass.
+    let mut expand = |sink: &mut dyn LineSink| {
+        let mut macros = MacroExpander::new();
+        let mut includes = Includes {
+            stack: vec![loader::normalize(Path::new(&options.file_name))],
+            files: &mut files,
+            options,
+        };
+        for (i, l) in source.lines().enumerate() {
+            macros.expand_line(l, file, i + 1, sink, &mut includes);
+        }
+        macros.finish(sink);
+    };
+    // The parser finds errors in several steps, so they are put in source
+    // order before the first `max_errors` are kept.
+    let first_errors = |errors: Vec<Diagnostic>, files: &SourceFiles| {
+        let mut errors: Vec<Diagnostic> = errors.into_iter().map(|e| files.locate(e)).collect();
+        errors.sort_by_key(|e| e.loc.as_ref().map(|loc| (loc.file, loc.line)));
+        errors.truncate(max_errors);
+        errors
+    };
+    parser
+        .parse(&mut expand, options.loader.as_ref())
+        .map_err(|e| first_errors(e, &files))?;
+    let rom = parser.gen_binary().map_err(|e| first_errors(e, &files))?;
+    let mut symbols: Vec<Symbol> = parser
+        .symbols()
+        .iter()
+        .filter(|(_, label)| label.exported)
+        .map(|(name, label)| Symbol {
+            name: name.clone(),
+            kind: label.kind,
+            bank: label.value.bank(),
+            address: label.value.word(),
+            prg_offset: match label
+                .value
+                .address()
+                .map(|a| (a, parser.header().rom_location(a)))
+            {
+                Some((address, Ok(RomLocation::Prg(bank, index)))) if address.address >= 0x8000 => {
+                    Some(bank * NesHeader::PRG_BANK_SIZE + index)
+                }
+                _ => None,
+            },
+            comment: label.comment.clone(),
+            references: label.references,
+            local: label.local,
+        })
+        .collect();
+    symbols.sort_by(|a, b| a.name.cmp(&b.name));
+    let listing = if options.listing {
+        Some(files.listing(parser.insts()))
+    } else {
+        None
+    };
+    let debug_info = if options.debug_info {
+        let rom_name = Path::new(&options.file_name).with_extension("nes");
+        Some(files.debug_info(&parser, &rom_name.to_string_lossy()))
+    } else {
+        None
+    };
+    let warnings = parser
+        .warnings()
+        .iter()
+        .map(|w| files.locate(w.clone()))
+        .collect();
+    let ram_usage = if options.ram_usage {
+        Some(parser.ram_usage())
+    } else {
+        None
+    };
+    Ok(Output {
+        rom,
+        symbols,
+        listing,
+        debug_info,
+        ram_usage,
+        warnings,
+    })
+}
+
+// A source file read during assembly.
+struct SourceFile {
+    path: String,
+    text: String,
+    // The `.include` that read the file.
+    included_from: Option<Loc>,
+}
+
+// The files of one assembly. `FileId`s index into it.
+#[derive(Default)]
+struct SourceFiles {
+    files: Vec<SourceFile>,
+}
+impl SourceFiles {
+    // Adds a file, or returns the one already read by the same `.include`
+    // in an earlier pass.
+    fn add(&mut self, path: String, text: String, included_from: Option<Loc>) -> FileId {
+        if let Some(i) = self
+            .files
+            .iter()
+            .position(|f| f.path == path && f.included_from == included_from)
+        {
+            return FileId(i);
+        }
+        self.files.push(SourceFile {
+            path,
+            text,
+            included_from,
+        });
+        FileId(self.files.len() - 1)
+    }
+    fn listing(&self, insts: &[AbstructInstruction]) -> String {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| listing::generate(insts, FileId(i), &file.path, &file.text))
+            .collect()
+    }
+    fn debug_info(&self, parser: &Parser, rom_name: &str) -> String {
+        let files: Vec<(&str, usize)> = self
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.text.len()))
+            .collect();
+        debug_info::ld65_dbg(
+            parser.insts(),
+            parser.header(),
+            parser.symbols(),
+            &files,
+            rom_name,
+        )
+    }
+    // Points a diagnostic at its file and quotes the offending line. Lines
+    // expanded from macros get a note for each invocation they came from,
+    // and lines of included files one for each `.include` on the way.
+    fn locate(&self, mut e: Diagnostic) -> Diagnostic {
+        let related = mem::take(&mut e.related);
+        e.related = related.into_iter().map(|r| self.excerpt(r)).collect();
+        let loc = match &e.loc {
+            Some(loc) if loc.is_known() => loc.clone(),
+            _ => return e,
+        };
+        let mut expansion = &loc.expansion;
+        let mut previous = None;
+        while let Some(exp) = expansion {
+            let call = Loc {
+                expansion: None,
+                ..exp.call.clone()
+            };
+            // A macro invoking itself repeats the same note, possibly with
+            // different arguments, so only the line is compared.
+            let line = (call.file, call.line);
+            if previous != Some(line) {
+                let note = Diagnostic::note(format!("in expansion of macro `{}`", exp.name));
+                e.related.push(self.excerpt(note.with_loc(call)));
+            }
+            previous = Some(line);
+            expansion = &exp.call.expansion;
+        }
+        let mut file = loc.file;
+        while let Some(from) = self
+            .files
+            .get(file.0)
+            .and_then(|f| f.included_from.as_ref())
+        {
+            let note = Diagnostic::note(format!("`{}` is included here", self.files[file.0].path));
+            let from = Loc {
+                expansion: None,
+                ..from.clone()
+            };
+            e.related.push(self.excerpt(note.with_loc(from.clone())));
+            file = from.file;
+        }
+        self.excerpt(e)
+    }
+    // Fills in the file name and source line of `e`.
+    fn excerpt(&self, e: Diagnostic) -> Diagnostic {
+        let loc = match &e.loc {
+            Some(loc) if loc.is_known() => loc.clone(),
+            _ => return e,
+        };
+        match self.files.get(loc.file.0) {
+            Some(file) => {
+                let source = file.text.lines().nth(loc.line - 1).unwrap_or("");
+                e.with_file(file.path.as_str()).with_source(source)
+            }
+            None => e,
+        }
+    }
+}
+
+// Reads `.include`d files, relative to the including file and then the
+// search paths, and records them in `files`.
+struct Includes<'a> {
+    files: &'a mut SourceFiles,
+    options: &'a Options,
+    // Normalized paths of the files being read, outermost first.
+    stack: Vec<PathBuf>,
+}
+impl Includer for Includes<'_> {
+    fn enter(&mut self, name: &str, loc: &Loc) -> Result<(FileId, String), Diagnostic> {
+        let including = Path::new(&self.files.files[loc.file.0].path);
+        let dir = including
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let dirs: Vec<PathBuf> = std::iter::once(dir)
+            .chain(self.options.search_paths.iter().cloned())
+            .collect();
+        let (path, data) = loader::find_file(self.options.loader.as_ref(), name, &dirs)?;
+        let normalized = loader::normalize(&path);
+        if self.stack.contains(&normalized) {
+            return Err(
+                Diagnostic::error(format!("`{}` is included recursively", path.display()))
+                    .with_loc(loc.clone()),
+            );
+        }
+        self.stack.push(normalized);
+        let text = String::from_utf8_lossy(&data).into_owned();
+        let path = path.to_string_lossy().into_owned();
+        let file = self.files.add(path, text.clone(), Some(loc.clone()));
+        Ok((file, text))
+    }
+    fn leave(&mut self) {
+        self.stack.pop();
+    }
+}
+
+pub struct Assembler {
+    max_errors: usize,
+}
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Assembler {
     pub fn new() -> Assembler {
-        Assembler {}
-    }
-    pub fn assemble(&mut self, asmfilepath: String, binfilepath: String) {
-        let infile = fs::File::open(asmfilepath.to_string()).unwrap();
-        let mut v: Vec<Vec<tokenizer::Token>> = Vec::new();
-        for result in BufReader::new(infile).lines() {
-            if let Ok(l) = result {
-                let tokens = tokenizer::tokenize(l.to_string());
-                debug!("{:?}", &tokens);
-                v.push(tokens);
-            }
+        Assembler {
+            max_errors: DEFAULT_MAX_ERRORS,
         }
-        let mut parser = Parser::new();
-        parser.set_base_path(&asmfilepath);
-        parser.parse(v);
-        let bin = parser.gen_binary();
-        let mut file = File::create(binfilepath).unwrap();
-        file.write_all(&bin).unwrap();
+    }
+    // Return only the first this many errors. 0 means no limit.
+    pub fn set_max_errors(&mut self, max_errors: usize) {
+        self.max_errors = if max_errors == 0 {
+            usize::MAX
+        } else {
+            max_errors
+        };
+    }
+    pub fn max_errors(&self) -> usize {
+        self.max_errors
+    }
+    pub fn assemble(
+        &mut self,
+        asmfilepath: String,
+        binfilepath: String,
+    ) -> Result<(), Vec<Diagnostic>> {
+        let options = Options {
+            file_name: asmfilepath,
+            max_errors: self.max_errors,
+            ..Default::default()
+        };
+        let source = options
+            .loader
+            .load(Path::new(&options.file_name))
+            .and_then(|bytes| {
+                String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .map_err(|e| {
+                vec![Diagnostic::error(format!(
+                    "cannot read `{}`: {}",
+                    options.file_name, e
+                ))]
+            })?;
+        let output = assemble_source(&source, &options)?;
+        let mut file = File::create(&binfilepath).map_err(|e| {
+            vec![Diagnostic::error(format!(
+                "cannot write `{}`: {}",
+                binfilepath, e
+            ))]
+        })?;
+        file.write_all(&output.rom).map_err(|e| {
+            vec![Diagnostic::error(format!(
+                "cannot write `{}`: {}",
+                binfilepath, e
+            ))]
+        })?;
+        Ok(())
     }
 }
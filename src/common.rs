@@ -1,10 +1,53 @@
+// Index of a source file in the assembler's file list.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct FileId(pub usize);
+
+// A column range on one line of one source file. Lines are 1-based and
+// columns are 0-based character offsets; line 0 means the location is unknown.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+pub struct Loc {
+    pub file: FileId,
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+    // The macro invocation this line was expanded from, if any.
+    pub expansion: Option<Box<Expansion>>,
+}
+
+// An invocation of macro `name` at `call`.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub struct Loc(pub usize, pub usize);
+pub struct Expansion {
+    pub name: String,
+    pub call: Loc,
+}
 impl Loc {
-    #[allow(dead_code)]
-    pub fn merge(&self, other: &Loc) {
+    pub fn new(file: FileId, line: usize, start: usize, end: usize) -> Loc {
+        Loc {
+            file,
+            line,
+            start,
+            end,
+            expansion: None,
+        }
+    }
+    pub fn is_known(&self) -> bool {
+        self.line != 0
+    }
+    pub fn merge(&self, other: &Loc) -> Loc {
         use std::cmp::{max, min};
-        Loc(min(self.0, other.0), max(self.1, other.1));
+        Loc {
+            start: min(self.start, other.start),
+            end: max(self.end, other.end),
+            ..self.clone()
+        }
+    }
+    // The line written in the source: the outermost macro invocation for
+    // expanded lines, otherwise the location itself.
+    pub fn call_site(&self) -> &Loc {
+        match &self.expansion {
+            Some(expansion) => expansion.call.call_site(),
+            None => self,
+        }
     }
 }
 
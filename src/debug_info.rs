@@ -0,0 +1,210 @@
+use crate::common::FileId;
+use crate::insts::{AbstructInstruction, RamAddress};
+use crate::nes_header::{NesHeader, RomLocation};
+use crate::symbol_table::{Label, SymbolKind, SymbolTable};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Generates ld65-style debug info (the `.dbg` format that Mesen loads for
+// source-level debugging).
+//
+// Each PRG bank becomes a segment per CPU base address it is assembled at,
+// every emitted instruction or data run becomes a span in its segment, and
+// spans are tied to their source line. All symbols live in one global scope;
+// a label's `size` covers the bytes up to the next label in its segment, so
+// every span is owned by the label it follows.
+// `files` holds the path and length of each source file, indexed by FileId.
+pub fn ld65_dbg(
+    insts: &[AbstructInstruction],
+    header: &NesHeader,
+    symtab: &SymbolTable,
+    files: &[(&str, usize)],
+    rom_name: &str,
+) -> String {
+    // (bank, base address) -> segment id
+    let mut segs: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+    // (segment, start, size) of each span
+    let mut spans: Vec<((usize, usize), usize, usize)> = vec![];
+    // (file, line) -> span ids
+    let mut lines: BTreeMap<(FileId, usize), Vec<usize>> = BTreeMap::new();
+    for inst in insts {
+        let (loc, address, size) = match inst {
+            AbstructInstruction::Instruction(inst) => {
+                (&inst.loc, &inst.address, inst.op_info().num_bytes as usize)
+            }
+            AbstructInstruction::Bin(bin) => (&bin.loc, &bin.address, bin.dat.len()),
+            AbstructInstruction::Data(_) => continue,
+        };
+        let seg = match prg_segment(header, address) {
+            Some(seg) if size > 0 => seg,
+            _ => continue,
+        };
+        let index = address.address as usize - seg.1;
+        segs.insert(seg, 0);
+        lines
+            .entry((loc.file, loc.line))
+            .or_default()
+            .push(spans.len());
+        spans.push((seg, index, size));
+    }
+    for (id, seg) in segs.values_mut().enumerate() {
+        *seg = id;
+    }
+
+    let mut symbols: Vec<_> = symtab.iter().filter(|(_, label)| label.exported).collect();
+    symbols.sort_by(|a, b| a.0.cmp(b.0));
+    for (_, label) in &symbols {
+        if label.loc.is_known() {
+            lines.entry((label.loc.file, label.loc.line)).or_default();
+        }
+    }
+    // CPU address just past the last emitted byte of each segment.
+    let mut seg_ends: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+    for ((bank, base), start, size) in &spans {
+        let end = seg_ends.entry((*bank, *base)).or_default();
+        *end = (*end).max(base + start + size);
+    }
+    // Label addresses per segment, to size each label.
+    let mut seg_labels: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+    for (_, label) in &symbols {
+        if let Some(seg) = label_segment(header, label, &segs) {
+            seg_labels
+                .entry(seg)
+                .or_default()
+                .push(label.value.word() as usize);
+        }
+    }
+    for addresses in seg_labels.values_mut() {
+        addresses.sort_unstable();
+    }
+    let line_ids: BTreeMap<(FileId, usize), usize> = lines
+        .keys()
+        .enumerate()
+        .map(|(id, key)| (*key, id))
+        .collect();
+
+    let mut out = String::new();
+    writeln!(out, "version\tmajor=2,minor=0").unwrap();
+    writeln!(
+        out,
+        "info\tcsym=0,file={},lib=0,line={},mod=1,scope=1,seg={},span={},sym={},type=0",
+        files.len(),
+        lines.len(),
+        segs.len(),
+        spans.len(),
+        symbols.len()
+    )
+    .unwrap();
+    for (id, (name, size)) in files.iter().enumerate() {
+        writeln!(
+            out,
+            "file\tid={},name=\"{}\",size={},mtime=0x00000000,mod=0",
+            id, name, size
+        )
+        .unwrap();
+    }
+    for (id, ((file, line), span_ids)) in lines.iter().enumerate() {
+        write!(out, "line\tid={},file={},line={}", id, file.0, line).unwrap();
+        if !span_ids.is_empty() {
+            write!(out, ",span={}", join_ids(span_ids)).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    writeln!(
+        out,
+        "mod\tid=0,name=\"{}\",file=0",
+        files.first().map_or("", |f| f.0)
+    )
+    .unwrap();
+    let total: usize = spans.iter().map(|span| span.2).sum();
+    write!(out, "scope\tid=0,name=\"\",mod=0,size={}", total).unwrap();
+    if !spans.is_empty() {
+        let ids: Vec<usize> = (0..spans.len()).collect();
+        write!(out, ",span={}", join_ids(&ids)).unwrap();
+    }
+    writeln!(out).unwrap();
+    for ((bank, base), id) in &segs {
+        writeln!(
+            out,
+            "seg\tid={},name=\"PRG{}_{:04X}\",start=0x{:06X},size=0x{:04X},addrsize=absolute,type=ro,oname=\"{}\",ooffs={}",
+            id,
+            bank,
+            base,
+            base,
+            NesHeader::PRG_BANK_SIZE,
+            rom_name,
+            NesHeader::SIZE + bank * NesHeader::PRG_BANK_SIZE
+        )
+        .unwrap();
+    }
+    for (id, (seg, start, size)) in spans.iter().enumerate() {
+        writeln!(
+            out,
+            "span\tid={},seg={},start={},size={}",
+            id, segs[seg], start, size
+        )
+        .unwrap();
+    }
+    for (id, (name, label)) in symbols.iter().enumerate() {
+        let value = label.value.word();
+        let addrsize = if value < 0x100 {
+            "zeropage"
+        } else {
+            "absolute"
+        };
+        write!(
+            out,
+            "sym\tid={},name=\"{}\",addrsize={},scope=0",
+            id, name, addrsize
+        )
+        .unwrap();
+        if let Some(line) = line_ids.get(&(label.loc.file, label.loc.line)) {
+            write!(out, ",def={}", line).unwrap();
+        }
+        write!(out, ",val=0x{:X}", value).unwrap();
+        let kind = match label.kind {
+            SymbolKind::Constant => "equ",
+            SymbolKind::Label | SymbolKind::Variable => "lab",
+        };
+        if let Some(seg) = label_segment(header, label, &segs) {
+            let value = value as usize;
+            let end = seg_labels[&seg]
+                .iter()
+                .find(|&&address| address > value)
+                .copied()
+                .unwrap_or(seg_ends[&seg]);
+            if end > value {
+                write!(out, ",size={}", end - value).unwrap();
+            }
+            write!(out, ",seg={}", segs[&seg]).unwrap();
+        }
+        writeln!(out, ",type={}", kind).unwrap();
+    }
+    out
+}
+
+// The (bank, base address) segment an address at $8000-$FFFF of a PRG bank
+// falls in.
+fn prg_segment(header: &NesHeader, address: &RamAddress) -> Option<(usize, usize)> {
+    match header.rom_location(address) {
+        Ok(RomLocation::Prg(bank, index)) if address.address >= 0x8000 => {
+            Some((bank, address.address as usize - index))
+        }
+        _ => None,
+    }
+}
+
+// The segment a label points into, if it labels emitted code or data.
+fn label_segment(
+    header: &NesHeader,
+    label: &Label,
+    segs: &BTreeMap<(usize, usize), usize>,
+) -> Option<(usize, usize)> {
+    let address = label.value.address()?;
+    prg_segment(header, address).filter(|seg| segs.contains_key(seg))
+}
+
+fn join_ids(ids: &[usize]) -> String {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    ids.join("+")
+}
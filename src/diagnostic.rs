@@ -0,0 +1,157 @@
+use crate::common::Loc;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A message about the source, printed rustc-style with the offending line
+/// and a caret underline when the location is known.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // Name of the file `loc` points into, filled in by the assembler.
+    pub file: Option<String>,
+    pub loc: Option<Loc>,
+    pub message: String,
+    pub note: Option<String>,
+    // Text of the source line, used for the excerpt.
+    pub source: Option<String>,
+    // Notes pointing at other places, such as the invocation of the macro
+    // the offending line came from. Printed after the diagnostic.
+    pub related: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            file: None,
+            loc: None,
+            message: message.into(),
+            note: None,
+            source: None,
+            related: vec![],
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn note(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Note, message)
+    }
+
+    pub fn with_loc(mut self, loc: Loc) -> Diagnostic {
+        self.loc = Some(loc);
+        self
+    }
+
+    // Uses `loc` unless the diagnostic already points somewhere more precise.
+    pub fn or_loc(self, loc: Loc) -> Diagnostic {
+        match &self.loc {
+            Some(l) if l.is_known() => self,
+            _ => self.with_loc(loc),
+        }
+    }
+
+    // 1-based line number, if known.
+    pub fn line(&self) -> Option<usize> {
+        self.loc
+            .as_ref()
+            .filter(|loc| loc.is_known())
+            .map(|loc| loc.line)
+    }
+
+    pub fn with_file(mut self, file: impl Into<String>) -> Diagnostic {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.note = Some(note.into());
+        self
+    }
+
+    pub fn with_source(mut self, source: impl Into<String>) -> Diagnostic {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn with_related(mut self, related: Diagnostic) -> Diagnostic {
+        self.related.push(related);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_one(f)?;
+        for related in &self.related {
+            write!(f, "{}", related)?;
+        }
+        Ok(())
+    }
+}
+
+impl Diagnostic {
+    fn fmt_one(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        let (line, loc) = match (self.line(), &self.loc) {
+            (Some(line), Some(loc)) => (line, loc),
+            _ => {
+                if let Some(file) = &self.file {
+                    writeln!(f, " --> {}", file)?;
+                }
+                if let Some(note) = &self.note {
+                    writeln!(f, " = note: {}", note)?;
+                }
+                return Ok(());
+            }
+        };
+        let column = loc.start + 1;
+        let gutter = " ".repeat(line.to_string().len());
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter,
+            self.file.as_deref().unwrap_or("<input>"),
+            line,
+            column
+        )?;
+        if let Some(source) = &self.source {
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", line, source)?;
+            // Keep tabs so that the carets line up with the source.
+            let indent: String = source
+                .chars()
+                .take(loc.start)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let width = std::cmp::max(loc.end.saturating_sub(loc.start), 1);
+            writeln!(f, "{} | {}{}", gutter, indent, "^".repeat(width))?;
+        }
+        if let Some(note) = &self.note {
+            writeln!(f, "{} = note: {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::str::FromStr;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub enum Directive {
     ORG,
@@ -13,6 +14,41 @@ pub enum Directive {
     BYTE,
     WORD,
     INCBIN,
+    INCLUDE,
+    EQU,
+    SET,
+    MACRO,
+    ENDM,
+    IF,
+    IFDEF,
+    IFNDEF,
+    ELSE,
+    ENDIF,
+    RS,
+    RSSET,
+    DS,
+    ZP,
+    BSS,
+    CODE,
+    DATA,
+    PROC,
+    ENDPROC,
+    SCOPE,
+    ENDSCOPE,
+}
+
+impl Directive {
+    // Whether this opens, continues or closes a conditional block.
+    pub fn is_conditional(&self) -> bool {
+        matches!(
+            self,
+            Directive::IF
+                | Directive::IFDEF
+                | Directive::IFNDEF
+                | Directive::ELSE
+                | Directive::ENDIF
+        )
+    }
 }
 
 impl FromStr for Directive {
@@ -31,6 +67,27 @@ impl FromStr for Directive {
             ".BYTE" => Ok(Directive::BYTE),
             ".WORD" => Ok(Directive::WORD),
             ".INCBIN" => Ok(Directive::INCBIN),
+            ".INCLUDE" => Ok(Directive::INCLUDE),
+            ".EQU" => Ok(Directive::EQU),
+            ".SET" => Ok(Directive::SET),
+            ".MACRO" => Ok(Directive::MACRO),
+            ".ENDM" | ".ENDMACRO" => Ok(Directive::ENDM),
+            ".IF" => Ok(Directive::IF),
+            ".IFDEF" => Ok(Directive::IFDEF),
+            ".IFNDEF" => Ok(Directive::IFNDEF),
+            ".ELSE" => Ok(Directive::ELSE),
+            ".ENDIF" => Ok(Directive::ENDIF),
+            ".RS" => Ok(Directive::RS),
+            ".RSSET" => Ok(Directive::RSSET),
+            ".DS" => Ok(Directive::DS),
+            ".ZP" => Ok(Directive::ZP),
+            ".BSS" => Ok(Directive::BSS),
+            ".CODE" => Ok(Directive::CODE),
+            ".DATA" => Ok(Directive::DATA),
+            ".PROC" => Ok(Directive::PROC),
+            ".ENDPROC" => Ok(Directive::ENDPROC),
+            ".SCOPE" => Ok(Directive::SCOPE),
+            ".ENDSCOPE" => Ok(Directive::ENDSCOPE),
             _ => Err(()),
         }
     }
@@ -0,0 +1,362 @@
+use crate::common::{Annot, Loc};
+use crate::diagnostic::Diagnostic;
+use crate::symbol_table::{self, SymbolTable};
+use crate::tokenizer::{Token, TokenKind};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    // `<expr` / `LOW(expr)`
+    Low,
+    // `>expr` / `HIGH(expr)`
+    High,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinaryOp {
+    // Binding power of each operator. Higher binds tighter (C-like ordering).
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 9,
+            BinaryOp::Add | BinaryOp::Sub => 8,
+            BinaryOp::Shl | BinaryOp::Shr => 7,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 6,
+            BinaryOp::Eq | BinaryOp::Ne => 5,
+            BinaryOp::And => 4,
+            BinaryOp::Xor => 3,
+            BinaryOp::Or => 2,
+        }
+    }
+
+    fn from_token(kind: &TokenKind) -> Option<BinaryOp> {
+        match kind {
+            TokenKind::Plus => Some(BinaryOp::Add),
+            TokenKind::Minus => Some(BinaryOp::Sub),
+            TokenKind::Star => Some(BinaryOp::Mul),
+            TokenKind::Slash => Some(BinaryOp::Div),
+            TokenKind::Percent => Some(BinaryOp::Mod),
+            TokenKind::Ampersand => Some(BinaryOp::And),
+            TokenKind::Pipe => Some(BinaryOp::Or),
+            TokenKind::Caret => Some(BinaryOp::Xor),
+            TokenKind::Shl => Some(BinaryOp::Shl),
+            TokenKind::Shr => Some(BinaryOp::Shr),
+            TokenKind::Eq => Some(BinaryOp::Eq),
+            TokenKind::Ne => Some(BinaryOp::Ne),
+            TokenKind::Lt => Some(BinaryOp::Lt),
+            TokenKind::Le => Some(BinaryOp::Le),
+            TokenKind::Gt => Some(BinaryOp::Gt),
+            TokenKind::Ge => Some(BinaryOp::Ge),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExprKind {
+    Number(i32),
+    // A symbol as written, with the scope it is looked up in first.
+    Symbol { name: String, scope: String },
+    UnaryOp(UnaryOp, Box<Expr>),
+    BinaryOp(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+pub type Expr = Annot<ExprKind>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExprErrorKind {
+    UnexpectedToken,
+    UnexpectedEnd,
+    UnclosedParen,
+    UndefinedSymbol(String),
+    DivisionByZero,
+}
+
+pub type ExprError = Annot<ExprErrorKind>;
+
+impl From<ExprError> for Diagnostic {
+    fn from(e: ExprError) -> Diagnostic {
+        let message = match e.value {
+            ExprErrorKind::UnexpectedToken => "unexpected token in expression".to_string(),
+            ExprErrorKind::UnexpectedEnd => "expected an expression".to_string(),
+            ExprErrorKind::UnclosedParen => "unclosed parenthesis".to_string(),
+            ExprErrorKind::UndefinedSymbol(name) => match symbol_table::anonymous_kind(&name) {
+                // References to earlier anonymous labels are checked while
+                // parsing, so only forward ones are left.
+                Some(kind) => format!("no anonymous label `{}` follows", kind),
+                None => format!("undefined symbol `{}`", name),
+            },
+            ExprErrorKind::DivisionByZero => "division by zero".to_string(),
+        };
+        Diagnostic::error(message).with_loc(e.loc)
+    }
+}
+
+impl Expr {
+    pub fn number(n: i32, loc: Loc) -> Self {
+        Self::new(ExprKind::Number(n), loc)
+    }
+
+    pub fn symbol(name: String, loc: Loc) -> Self {
+        Self::new(
+            ExprKind::Symbol {
+                name,
+                scope: String::new(),
+            },
+            loc,
+        )
+    }
+
+    /// Parses a whole token slice as one expression.
+    pub fn parse(tokens: &[Token]) -> Result<Expr, ExprError> {
+        let mut pos = 0;
+        let expr = parse_binary(tokens, &mut pos, 0)?;
+        if pos < tokens.len() {
+            return Err(ExprError::new(
+                ExprErrorKind::UnexpectedToken,
+                tokens[pos].loc.clone(),
+            ));
+        }
+        Ok(expr)
+    }
+
+    /// True when the value always fits in one byte, e.g. `<label`.
+    pub fn is_byte(&self) -> bool {
+        matches!(
+            self.value,
+            ExprKind::UnaryOp(UnaryOp::Low, _) | ExprKind::UnaryOp(UnaryOp::High, _)
+        )
+    }
+
+    /// Looks symbols up in `scope` (like `a::b`) first, and qualifies local
+    /// labels with `global`, the label they belong to.
+    pub fn set_scope(&mut self, scope: &str, global: &str) {
+        match &mut self.value {
+            ExprKind::Number(_) => {}
+            ExprKind::Symbol { name, .. } if symbol_table::is_local(name) => {
+                *name = format!("{}{}", global, name);
+            }
+            ExprKind::Symbol { scope: s, .. } => *s = scope.to_string(),
+            ExprKind::UnaryOp(_, e) => e.set_scope(scope, global),
+            ExprKind::BinaryOp(_, l, r) => {
+                l.set_scope(scope, global);
+                r.set_scope(scope, global);
+            }
+        }
+    }
+
+    /// Calls `f` with the name of every symbol, which it may change.
+    pub fn rename_symbols(&mut self, f: &mut dyn FnMut(&mut String, &Loc)) {
+        match &mut self.value {
+            ExprKind::Number(_) => {}
+            ExprKind::Symbol { name, .. } => f(name, &self.loc),
+            ExprKind::UnaryOp(_, e) => e.rename_symbols(f),
+            ExprKind::BinaryOp(_, l, r) => {
+                l.rename_symbols(f);
+                r.rename_symbols(f);
+            }
+        }
+    }
+
+    /// Every symbol the expression uses, with the scope it is used in, in
+    /// order of appearance.
+    pub fn symbols(&self) -> Vec<(&str, &str)> {
+        match &self.value {
+            ExprKind::Number(_) => vec![],
+            ExprKind::Symbol { name, scope } => vec![(name.as_str(), scope.as_str())],
+            ExprKind::UnaryOp(_, e) => e.symbols(),
+            ExprKind::BinaryOp(_, l, r) => {
+                let mut symbols = l.symbols();
+                symbols.extend(r.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Replaces symbols defined with `.set` by their current value, since
+    /// they may be given another value further down. Each one replaced
+    /// counts as a reference.
+    pub fn bind(&mut self, symtab: &mut SymbolTable) {
+        match &mut self.value {
+            ExprKind::Number(_) => {}
+            ExprKind::Symbol { name, scope } => {
+                if let Some(label) = symtab.lookup(name, scope).filter(|label| label.redefinable) {
+                    let value = label.value.number();
+                    symtab.reference(name, scope);
+                    self.value = ExprKind::Number(value);
+                }
+            }
+            ExprKind::UnaryOp(_, e) => e.bind(symtab),
+            ExprKind::BinaryOp(_, l, r) => {
+                l.bind(symtab);
+                r.bind(symtab);
+            }
+        }
+    }
+
+    pub fn eval(&self, symtab: &SymbolTable) -> Result<i32, ExprError> {
+        match &self.value {
+            ExprKind::Number(n) => Ok(*n),
+            ExprKind::Symbol { name, scope } => match symtab.lookup(name, scope) {
+                Some(label) => Ok(label.value.number()),
+                None => Err(ExprError::new(
+                    ExprErrorKind::UndefinedSymbol(name.clone()),
+                    self.loc.clone(),
+                )),
+            },
+            ExprKind::UnaryOp(op, e) => {
+                let v = e.eval(symtab)?;
+                Ok(match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => !v,
+                    UnaryOp::Low => v & 0xFF,
+                    UnaryOp::High => (v >> 8) & 0xFF,
+                })
+            }
+            ExprKind::BinaryOp(op, l, r) => {
+                let l = l.eval(symtab)?;
+                let r = r.eval(symtab)?;
+                Ok(match op {
+                    BinaryOp::Add => l.wrapping_add(r),
+                    BinaryOp::Sub => l.wrapping_sub(r),
+                    BinaryOp::Mul => l.wrapping_mul(r),
+                    BinaryOp::Div | BinaryOp::Mod if r == 0 => {
+                        return Err(ExprError::new(
+                            ExprErrorKind::DivisionByZero,
+                            self.loc.clone(),
+                        ))
+                    }
+                    BinaryOp::Div => l.wrapping_div(r),
+                    BinaryOp::Mod => l.wrapping_rem(r),
+                    BinaryOp::And => l & r,
+                    BinaryOp::Or => l | r,
+                    BinaryOp::Xor => l ^ r,
+                    BinaryOp::Shl => l.wrapping_shl(r as u32),
+                    BinaryOp::Shr => l.wrapping_shr(r as u32),
+                    BinaryOp::Eq => (l == r) as i32,
+                    BinaryOp::Ne => (l != r) as i32,
+                    BinaryOp::Lt => (l < r) as i32,
+                    BinaryOp::Le => (l <= r) as i32,
+                    BinaryOp::Gt => (l > r) as i32,
+                    BinaryOp::Ge => (l >= r) as i32,
+                })
+            }
+        }
+    }
+}
+
+// Precedence climbing over the binary operators.
+fn parse_binary(tokens: &[Token], pos: &mut usize, min_prec: u8) -> Result<Expr, ExprError> {
+    let mut lhs = parse_unary(tokens, pos)?;
+    while *pos < tokens.len() {
+        let op = match BinaryOp::from_token(&tokens[*pos].value) {
+            Some(op) if op.precedence() > min_prec => op,
+            _ => break,
+        };
+        *pos += 1;
+        let rhs = parse_binary(tokens, pos, op.precedence())?;
+        let loc = lhs.loc.merge(&rhs.loc);
+        lhs = Expr::new(ExprKind::BinaryOp(op, Box::new(lhs), Box::new(rhs)), loc);
+    }
+    Ok(lhs)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr, ExprError> {
+    let token = match tokens.get(*pos) {
+        Some(token) => token,
+        None => {
+            return Err(ExprError::new(
+                ExprErrorKind::UnexpectedEnd,
+                end_loc(tokens),
+            ))
+        }
+    };
+    let op = match token.value {
+        TokenKind::Minus => UnaryOp::Neg,
+        TokenKind::Tilde => UnaryOp::Not,
+        TokenKind::Lt => UnaryOp::Low,
+        TokenKind::Gt => UnaryOp::High,
+        _ => return parse_primary(tokens, pos),
+    };
+    *pos += 1;
+    let e = parse_unary(tokens, pos)?;
+    let loc = token.loc.merge(&e.loc);
+    Ok(Expr::new(ExprKind::UnaryOp(op, Box::new(e)), loc))
+}
+
+fn parse_primary(tokens: &[Token], pos: &mut usize) -> Result<Expr, ExprError> {
+    let token = &tokens[*pos];
+    *pos += 1;
+    match &token.value {
+        TokenKind::U8(n) => Ok(Expr::number(i32::from(*n), token.loc.clone())),
+        TokenKind::U16(n) => Ok(Expr::number(i32::from(*n), token.loc.clone())),
+        TokenKind::Label(label) => {
+            let name = label.iter().collect::<String>();
+            let func = match name.to_uppercase().as_str() {
+                "LOW" => Some(UnaryOp::Low),
+                "HIGH" => Some(UnaryOp::High),
+                _ => None,
+            };
+            match (func, tokens.get(*pos)) {
+                (
+                    Some(op),
+                    Some(Token {
+                        value: TokenKind::LParen,
+                        ..
+                    }),
+                ) => {
+                    let e = parse_primary(tokens, pos)?;
+                    let loc = token.loc.merge(&e.loc);
+                    Ok(Expr::new(ExprKind::UnaryOp(op, Box::new(e)), loc))
+                }
+                _ => Ok(Expr::symbol(name, token.loc.clone())),
+            }
+        }
+        TokenKind::LParen => {
+            let e = parse_binary(tokens, pos, 0)?;
+            match tokens.get(*pos) {
+                Some(Token {
+                    value: TokenKind::RParen,
+                    loc,
+                }) => {
+                    *pos += 1;
+                    Ok(Expr::new(e.value, token.loc.merge(loc)))
+                }
+                _ => Err(ExprError::new(
+                    ExprErrorKind::UnclosedParen,
+                    token.loc.clone(),
+                )),
+            }
+        }
+        _ => Err(ExprError::new(
+            ExprErrorKind::UnexpectedToken,
+            token.loc.clone(),
+        )),
+    }
+}
+
+fn end_loc(tokens: &[Token]) -> Loc {
+    match tokens.last() {
+        Some(token) => Loc::new(token.loc.file, token.loc.line, token.loc.end, token.loc.end),
+        None => Loc::default(),
+    }
+}
@@ -1,5 +1,8 @@
+use crate::common::Loc;
 use crate::directive::Directive;
+use crate::expr::Expr;
 use std::convert::TryFrom;
+use std::fmt;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct RamAddress {
@@ -7,17 +10,11 @@ pub struct RamAddress {
     pub address: u16,
 }
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub enum AbstructAddress {
-    RamAddress(RamAddress),
-    Label(String),
-}
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Operand {
-    U8(u8),
-    U16(u16),
     Im(u16),
-    String(Vec<char>),
-    Address(AbstructAddress),
+    Address(RamAddress),
+    // Not yet evaluated. Replaced by `Im` or `Address` in `resolve_address`.
+    Expr(Expr),
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -26,6 +23,7 @@ pub struct OpInfo {
     pub num_cycles: u8,
     pub opcode: u8,
 }
+#[allow(clippy::upper_case_acronyms)]
 #[derive(strum_macros::Display, Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Opcode {
     ADC,
@@ -153,7 +151,7 @@ impl FromStr for Opcode {
         }
     }
 }
-#[derive(strum_macros::Display, Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Addressing {
     Implied,
     Accumulator,
@@ -170,22 +168,72 @@ pub enum Addressing {
     IndirectY,
 }
 
+// How diagnostics name the mode.
+impl fmt::Display for Addressing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Addressing::Implied => "implied",
+            Addressing::Accumulator => "accumulator",
+            Addressing::Immediate => "immediate",
+            Addressing::Zeropage => "zero page",
+            Addressing::ZeropageX => "zero page,X",
+            Addressing::ZeropageY => "zero page,Y",
+            Addressing::Relative => "relative",
+            Addressing::Absolute => "absolute",
+            Addressing::AbsoluteX => "absolute,X",
+            Addressing::AbsoluteY => "absolute,Y",
+            Addressing::Indirect => "indirect",
+            Addressing::IndirectX => "(zero page,X)",
+            Addressing::IndirectY => "(zero page),Y",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Addressing {
+    // The absolute mode a zero page mode widens to.
+    pub fn widened(&self) -> Option<Addressing> {
+        match self {
+            Addressing::Zeropage => Some(Addressing::Absolute),
+            Addressing::ZeropageX => Some(Addressing::AbsoluteX),
+            Addressing::ZeropageY => Some(Addressing::AbsoluteY),
+            _ => None,
+        }
+    }
+    // The zero page mode an absolute mode narrows to.
+    pub fn narrowed(&self) -> Option<Addressing> {
+        match self {
+            Addressing::Absolute => Some(Addressing::Zeropage),
+            Addressing::AbsoluteX => Some(Addressing::ZeropageX),
+            Addressing::AbsoluteY => Some(Addressing::ZeropageY),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum AbstructInstruction {
     Instruction(Instruction),
     Bin(Bin),
-    Label(Label),
+    Data(Data),
 }
+// `.db`/`.dw` values that are evaluated into a `Bin` once labels are known.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub struct Label {
-    pub label: AbstructAddress,
+pub struct Data {
+    pub exprs: Vec<Expr>,
+    // Bytes emitted per expression (1 for .db, 2 for .dw).
+    pub size: u8,
     pub address: RamAddress,
+    // Source statement this was assembled from.
+    pub loc: Loc,
 }
-impl Label {
-    pub fn new(label: AbstructAddress, address: RamAddress) -> Label {
-        Label {
-            label: label,
-            address: address,
+impl Data {
+    pub fn new(exprs: Vec<Expr>, size: u8, address: RamAddress, loc: Loc) -> Data {
+        Data {
+            exprs,
+            size,
+            address,
+            loc,
         }
     }
 }
@@ -193,13 +241,12 @@ impl Label {
 pub struct Bin {
     pub dat: Vec<u8>,
     pub address: RamAddress,
+    // Source statement this was assembled from.
+    pub loc: Loc,
 }
 impl Bin {
-    pub fn new(dat: Vec<u8>, address: RamAddress) -> Bin {
-        Bin {
-            dat: dat,
-            address: address,
-        }
+    pub fn new(dat: Vec<u8>, address: RamAddress, loc: Loc) -> Bin {
+        Bin { dat, address, loc }
     }
 }
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -208,6 +255,8 @@ pub struct Instruction {
     pub addressing: Addressing,
     pub operand: Option<Operand>,
     pub address: RamAddress,
+    // Source statement this was assembled from.
+    pub loc: Loc,
 }
 
 impl Instruction {
@@ -216,49 +265,50 @@ impl Instruction {
         addressing: Addressing,
         operand: Option<Operand>,
         address: RamAddress,
+        loc: Loc,
     ) -> Instruction {
         Instruction {
-            opcode: opcode,
-            addressing: addressing,
-            operand: operand,
-            address: address,
+            opcode,
+            addressing,
+            operand,
+            address,
+            loc,
         }
     }
     pub fn get_inst_code(&self) -> Vec<u8> {
-        if let None = self.operand {
-            return vec![self.get_op_info().opcode];
-        }
-        let address = match self.operand.as_ref().unwrap() {
-            Operand::Address(address) => {
-                match address {
-                    AbstructAddress::RamAddress(ram_address) => ram_address.address,
-                    AbstructAddress::Label(_label) => 0, // todo label解決
-                }
-            }
-            Operand::Im(im) => im.clone(),
-            _ => panic!(),
+        let address = match &self.operand {
+            None => return vec![self.op_info().opcode],
+            Some(Operand::Address(ram_address)) => ram_address.address,
+            Some(Operand::Im(im)) => *im,
+            Some(Operand::Expr(_)) => 0, // not resolved yet
         };
 
-        let info = self.get_op_info();
+        let info = self.op_info();
         let mut v = vec![info.opcode];
         match info.num_bytes {
-            1 => return v,
+            1 => v,
             2 => {
                 let operand: u8 = TryFrom::try_from(address % 256).unwrap();
                 v.push(operand);
-                return v;
+                v
             }
             3 => {
                 let bytes = address.to_le_bytes(); // read as little endian
                 v.push(bytes[0]);
                 v.push(bytes[1]);
-                return v;
+                v
             }
             _ => panic!(),
         }
     }
-    pub fn get_op_info(&self) -> OpInfo {
-        match self.opcode {
+    // Only called for instructions whose addressing mode was checked by the
+    // parser.
+    pub fn op_info(&self) -> OpInfo {
+        self.get_op_info().expect("invalid addressing mode")
+    }
+    // Returns `None` if the opcode has no encoding for the addressing mode.
+    pub fn get_op_info(&self) -> Option<OpInfo> {
+        Some(match self.opcode {
             Opcode::ADC => match self.addressing {
                 Addressing::Immediate => OpInfo {
                     num_bytes: 2,
@@ -300,7 +350,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0x71,
                 },
-                _ => panic!("Invalid inst_code"),
+                _ => return None,
             },
             Opcode::AND => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -343,7 +393,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0x31,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::ASL => match self.addressing {
                 Addressing::Accumulator => OpInfo {
@@ -371,7 +421,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0x1e,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BCC => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -379,7 +429,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x90,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BCS => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -387,7 +437,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xb0,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BEQ => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -395,7 +445,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xf0,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BIT => match self.addressing {
                 Addressing::Zeropage => OpInfo {
@@ -408,7 +458,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0x2c,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BMI => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -416,7 +466,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x30,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BNE => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -424,7 +474,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xd0,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BPL => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -432,7 +482,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x10,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BRK => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -440,7 +490,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0x00,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BVC => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -448,7 +498,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x50,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::BVS => match self.addressing {
                 Addressing::Relative => OpInfo {
@@ -456,7 +506,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x70,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CLC => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -464,7 +514,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x18,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CLD => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -472,7 +522,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xd8,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CLI => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -480,7 +530,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x58,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CLV => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -488,7 +538,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xb8,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CMP => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -531,7 +581,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0xd1,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CPX => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -549,7 +599,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0xec,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::CPY => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -567,7 +617,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0xcc,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::DEC => match self.addressing {
                 Addressing::Zeropage => OpInfo {
@@ -590,7 +640,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0xde,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::DEX => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -598,7 +648,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xca,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::DEY => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -606,7 +656,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x88,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::EOR => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -649,7 +699,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0x51,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::INC => match self.addressing {
                 Addressing::Zeropage => OpInfo {
@@ -672,7 +722,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0xfe,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::INX => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -680,7 +730,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xe8,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::INY => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -688,7 +738,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xc8,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::JMP => match self.addressing {
                 Addressing::Absolute => OpInfo {
@@ -701,7 +751,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0x6c,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::JSR => match self.addressing {
                 Addressing::Absolute => OpInfo {
@@ -709,7 +759,7 @@ impl Instruction {
                     num_cycles: 6,
                     opcode: 0x20,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::LDA => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -752,7 +802,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0xb1,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::LDX => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -780,7 +830,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0xbe,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::LDY => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -808,7 +858,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0xbc,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::LSR => match self.addressing {
                 Addressing::Accumulator => OpInfo {
@@ -836,7 +886,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0x5e,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::NOP => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -844,7 +894,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xea,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::ORA => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -887,7 +937,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0x11,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::PHA => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -895,7 +945,7 @@ impl Instruction {
                     num_cycles: 3,
                     opcode: 0x48,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::PHP => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -903,7 +953,7 @@ impl Instruction {
                     num_cycles: 3,
                     opcode: 0x08,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::PLA => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -911,7 +961,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0x68,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::PLP => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -919,7 +969,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0x28,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::ROL => match self.addressing {
                 Addressing::Accumulator => OpInfo {
@@ -947,7 +997,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0x3e,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::ROR => match self.addressing {
                 Addressing::Accumulator => OpInfo {
@@ -975,7 +1025,7 @@ impl Instruction {
                     num_cycles: 7,
                     opcode: 0x7e,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::RTI => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -983,7 +1033,7 @@ impl Instruction {
                     num_cycles: 6,
                     opcode: 0x40,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::RTS => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -991,7 +1041,7 @@ impl Instruction {
                     num_cycles: 6,
                     opcode: 0x60,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::SBC => match self.addressing {
                 Addressing::Immediate => OpInfo {
@@ -1034,7 +1084,7 @@ impl Instruction {
                     num_cycles: 5,
                     opcode: 0xf1,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::SEC => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1042,7 +1092,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x38,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::SED => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1050,7 +1100,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xf8,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::SEI => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1058,7 +1108,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x78,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::STA => match self.addressing {
                 Addressing::Zeropage => OpInfo {
@@ -1096,7 +1146,7 @@ impl Instruction {
                     num_cycles: 6,
                     opcode: 0x91,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::STX => match self.addressing {
                 Addressing::Zeropage => OpInfo {
@@ -1114,7 +1164,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0x8e,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::STY => match self.addressing {
                 Addressing::Zeropage => OpInfo {
@@ -1132,7 +1182,7 @@ impl Instruction {
                     num_cycles: 4,
                     opcode: 0x8c,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::TAX => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1140,7 +1190,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xaa,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::TAY => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1148,7 +1198,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xa8,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::TSX => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1156,7 +1206,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0xba,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::TXA => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1164,7 +1214,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x8a,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::TXS => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1172,7 +1222,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x9a,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::TYA => match self.addressing {
                 Addressing::Implied => OpInfo {
@@ -1180,7 +1230,7 @@ impl Instruction {
                     num_cycles: 2,
                     opcode: 0x98,
                 },
-                _ => panic!("invalid inst_code"),
+                _ => return None,
             },
             Opcode::DIRECTIVE(directive) => {
                 let num_bytes = match directive {
@@ -1200,6 +1250,6 @@ impl Instruction {
                     opcode: 0x00,
                 }
             }
-        }
+        })
     }
 }
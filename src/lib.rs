@@ -1,9 +1,24 @@
+// Diagnostics are returned by value on the error path only.
+#![allow(clippy::result_large_err)]
 extern crate regex;
 pub mod assembler;
 mod common;
+mod debug_info;
+pub mod diagnostic;
 mod directive;
+mod expr;
 mod insts;
+mod listing;
+pub mod loader;
+mod macros;
 mod nes_header;
 mod parser;
+mod ram;
+pub mod symbol_file;
 mod symbol_table;
 mod tokenizer;
+
+pub use assembler::{assemble_source, Options, Output, OutputFormat, Symbol};
+pub use diagnostic::Diagnostic;
+pub use loader::{FsLoader, MemoryLoader, SourceLoader};
+pub use symbol_table::SymbolKind;
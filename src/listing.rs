@@ -0,0 +1,65 @@
+use crate::common::FileId;
+use crate::insts::{AbstructInstruction, RamAddress};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Bytes shown per listing row. Longer runs continue on following rows.
+const BYTES_PER_ROW: usize = 8;
+
+// Generates a NESASM-style listing of one source file: every line with the
+// bank, CPU address and bytes it emitted, followed by its text.
+//
+//     #[1]   main.asm
+//         1                                        .org $C000
+//         2  00:C000  A9 42                    START: LDA #$42
+pub fn generate(insts: &[AbstructInstruction], file: FileId, path: &str, text: &str) -> String {
+    // Emitted bytes of each line, in address order.
+    let mut emitted: BTreeMap<usize, Vec<(&RamAddress, Vec<u8>)>> = BTreeMap::new();
+    for inst in insts {
+        let (loc, address, dat) = match inst {
+            AbstructInstruction::Instruction(inst) => {
+                (&inst.loc, &inst.address, inst.get_inst_code())
+            }
+            AbstructInstruction::Bin(bin) => (&bin.loc, &bin.address, bin.dat.clone()),
+            AbstructInstruction::Data(_) => continue,
+        };
+        // Expanded macro code is listed at the invocation.
+        let loc = loc.call_site();
+        if loc.file == file {
+            emitted.entry(loc.line).or_default().push((address, dat));
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "#[{}]   {}", file.0 + 1, path).unwrap();
+    for (i, source) in text.lines().enumerate() {
+        let line = i + 1;
+        let mut rows = vec![];
+        for (address, dat) in emitted.get(&line).into_iter().flatten() {
+            for (n, chunk) in dat.chunks(BYTES_PER_ROW).enumerate() {
+                let addr = address.address.wrapping_add((n * BYTES_PER_ROW) as u16);
+                rows.push((address.bank, addr, chunk));
+            }
+        }
+        match rows.split_first() {
+            None => {
+                let text = format!("{:5}  {:32}  {}", line, "", source);
+                writeln!(out, "{}", text.trim_end()).unwrap();
+            }
+            Some((first, rest)) => {
+                let text = format!("{:5}  {}  {}", line, row(first), source);
+                writeln!(out, "{}", text.trim_end()).unwrap();
+                for r in rest {
+                    writeln!(out, "{:5}  {}", "", row(r).trim_end()).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+// `BB:AAAA  XX XX ...`, padded to a fixed width.
+fn row(&(bank, address, bytes): &(u8, u16, &[u8])) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("{:02X}:{:04X}  {:23}", bank, address, hex.join(" "))
+}
@@ -0,0 +1,97 @@
+use crate::diagnostic::Diagnostic;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Reads the files that a source pulls in (such as `.incbin` data) on behalf
+/// of the assembler, so that they need not come from the disk.
+pub trait SourceLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>>;
+}
+
+/// Reads files from the filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsLoader;
+impl SourceLoader for FsLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+/// Serves files from memory. `./` and `..` in paths are resolved before
+/// lookup, so `data/../a.bin` finds `a.bin`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader::default()
+    }
+    pub fn insert(&mut self, path: impl AsRef<Path>, data: impl Into<Vec<u8>>) {
+        self.files.insert(normalize(path.as_ref()), data.into());
+    }
+}
+impl SourceLoader for MemoryLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file in memory"))
+    }
+}
+
+// Reads `name` from the first of `dirs` that has it, returning the path it
+// was found at. Only a missing file moves on to the next directory.
+pub(crate) fn find_file(
+    loader: &dyn SourceLoader,
+    name: &str,
+    dirs: &[PathBuf],
+) -> Result<(PathBuf, Vec<u8>), Diagnostic> {
+    let candidates: Vec<PathBuf> = dirs.iter().map(|dir| dir.join(name)).collect();
+    let mut first_error = None;
+    for path in &candidates {
+        match loader.load(path) {
+            Ok(data) => return Ok((path.clone(), data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                first_error.get_or_insert(e);
+            }
+            Err(e) => {
+                return Err(Diagnostic::error(format!(
+                    "cannot read `{}`: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        }
+    }
+    let e = Diagnostic::error(format!(
+        "cannot read `{}`: {}",
+        candidates[0].display(),
+        first_error.expect("at least one path is tried")
+    ));
+    if candidates.len() > 1 {
+        let searched: Vec<String> = candidates[1..]
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(e.with_note(format!("also searched {}", searched.join(", "))));
+    }
+    Err(e)
+}
+
+// Resolves `.` and `..` without touching the filesystem.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
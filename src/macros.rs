@@ -0,0 +1,469 @@
+use crate::common::{Expansion, FileId, Loc};
+use crate::diagnostic::Diagnostic;
+use crate::directive::Directive;
+use crate::tokenizer::{self, Token, TokenKind};
+use log::debug;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Invocations nested deeper than this are taken for runaway recursion.
+pub const MAX_EXPANSION_DEPTH: usize = 32;
+
+// A line of a macro body, kept as text until the macro is invoked.
+#[derive(Debug, Clone)]
+struct BodyLine {
+    text: String,
+    file: FileId,
+    line: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    // The name in the definition.
+    loc: Loc,
+    body: Vec<BodyLine>,
+}
+
+// Receives the expanded token lines one at a time, as the parser reads them.
+// `.include`s and macro invocations are only expanded while the sink is
+// active, so that they can be skipped by conditional assembly.
+pub trait LineSink {
+    fn line(&mut self, tokens: Vec<Token>);
+    // Whether the current conditional block is being assembled.
+    fn is_active(&self) -> bool;
+    fn error(&mut self, e: Diagnostic);
+}
+
+// Reads the source files named by `.include`.
+pub trait Includer {
+    // Reads `name`, included at `loc`, and returns the id and text of the
+    // file. Each successful call is followed by `leave` once the file has
+    // been expanded.
+    fn enter(&mut self, name: &str, loc: &Loc) -> Result<(FileId, String), Diagnostic>;
+    fn leave(&mut self);
+}
+
+// Expands NESASM-style macros and `.include`s while tokenizing the source:
+//
+//     store .macro         ; or `.macro store`
+//         LDA \1           ; \1-\9: arguments, \#: number of arguments,
+//         STA \2           ; \@: a suffix unique to each invocation
+//         .endm
+//         store #$00, $2001
+//
+// Definitions are removed from the output and every invocation is replaced
+// by the token lines of the body, with the arguments substituted as text.
+// `.include "file"` is replaced by the expanded lines of the file.
+// Tokens of expanded lines point at the body line and remember the
+// invocation they came from. Lines are handed to the sink as soon as they
+// are expanded, so a `.if` in a macro body can stop a recursive invocation
+// that follows it.
+#[derive(Debug, Default)]
+pub struct MacroExpander {
+    macros: HashMap<String, Macro>,
+    // The macro being defined, and how many `.macro`s inside its body are
+    // still open.
+    defining: Option<(String, Macro, usize)>,
+    // Whether the macro being defined is in an active conditional block;
+    // definitions in skipped blocks are dropped at their `.endm`.
+    keeping: bool,
+    invocations: usize,
+    // Set when an expansion hits `MAX_EXPANSION_DEPTH`, to abandon the rest
+    // of it.
+    runaway: bool,
+}
+
+impl MacroExpander {
+    pub fn new() -> MacroExpander {
+        Default::default()
+    }
+
+    // Tokenizes line `line_no` (1-based) of `file`, expanding any macro
+    // invocation or `.include`, and hands the resulting token lines to
+    // `out`.
+    pub fn expand_line(
+        &mut self,
+        text: &str,
+        file: FileId,
+        line_no: usize,
+        out: &mut dyn LineSink,
+        includer: &mut dyn Includer,
+    ) {
+        self.runaway = false;
+        self.process(text, &Loc::new(file, line_no, 0, 0), None, 0, out, includer);
+    }
+
+    // Reports a definition left open at the end of the source.
+    pub fn finish(&mut self, out: &mut dyn LineSink) {
+        if let Some((name, def, _)) = self.defining.take() {
+            out.error(
+                Diagnostic::error(format!("macro `{}` has no matching `.endm`", name))
+                    .with_loc(def.loc),
+            );
+        }
+    }
+
+    // `line` gives the file and line of `text` and the invocation it was
+    // expanded from. `columns` maps the columns of a substituted body line
+    // back to the body line, as returned by `substitute`.
+    fn process(
+        &mut self,
+        text: &str,
+        line: &Loc,
+        columns: Option<&[(usize, usize)]>,
+        depth: usize,
+        out: &mut dyn LineSink,
+        includer: &mut dyn Includer,
+    ) {
+        if self.defining.is_some() {
+            self.collect(text, line);
+            return;
+        }
+        let raw = match tokenizer::tokenize(text, line.file, line.line) {
+            Ok(tokens) => tokens,
+            Err(mut e) => {
+                e.loc.expansion = line.expansion.clone();
+                out.error(Diagnostic::from(e));
+                return;
+            }
+        };
+        // Tokens as they are handed out: pointing at the body line and the
+        // invocation. `raw` keeps the columns of `text`.
+        let tokens: Vec<Token> = raw
+            .iter()
+            .map(|t| {
+                let mut t = t.clone();
+                t.loc = body_loc(&t.loc, columns);
+                t.loc.expansion = line.expansion.clone();
+                t
+            })
+            .collect();
+        debug!("{:?}", &tokens);
+        let stmt: Vec<&Token> = tokens
+            .iter()
+            .filter(|t| !matches!(t.value, TokenKind::Comment(_)))
+            .collect();
+        let pos = match stmt.first().map(|t| &t.value) {
+            Some(TokenKind::LabelDef(_)) => 1,
+            _ => 0,
+        };
+        let active = out.is_active();
+        match stmt.get(pos).and_then(|t| directive(t)) {
+            Some(Directive::MACRO) => {
+                self.keeping = active;
+                match (pos, &stmt[pos + 1..]) {
+                    (1, []) => self.define(label_name(stmt[0]), stmt[0].loc.clone(), out),
+                    (0, [name]) if matches!(name.value, TokenKind::Label(_)) => {
+                        self.define(label_name(name), name.loc.clone(), out)
+                    }
+                    _ if !active => {}
+                    _ => out.error(
+                        Diagnostic::error("`.macro` expects a name")
+                            .with_loc(stmt[pos].loc.clone())
+                            .with_note("example: name .macro"),
+                    ),
+                }
+                return;
+            }
+            Some(Directive::ENDM) if active => {
+                out.error(
+                    Diagnostic::error("`.endm` without a matching `.macro`")
+                        .with_loc(stmt[pos].loc.clone()),
+                );
+                return;
+            }
+            Some(Directive::INCLUDE) if active => {
+                if pos == 1 {
+                    out.line(label_line(&tokens));
+                }
+                match &stmt[pos + 1..] {
+                    [Token {
+                        value: TokenKind::String(name),
+                        loc,
+                    }] => {
+                        let name: String = name.iter().collect();
+                        self.include(&name, loc, depth, out, includer);
+                    }
+                    args => out.error(
+                        Diagnostic::error("`.include` expects a file name")
+                            .with_loc(stmt[pos].loc.merge(&args.last().unwrap_or(&stmt[pos]).loc))
+                            .with_note("example: .include \"constants.asm\""),
+                    ),
+                }
+                return;
+            }
+            _ => {}
+        }
+        // A label at the start of the line can itself be the invocation.
+        let call = match stmt.first().map(|t| &t.value) {
+            Some(TokenKind::LabelDef(name)) if name.last() != Some(&':') && self.is_macro(name) => {
+                Some(0)
+            }
+            _ => match stmt.get(pos).map(|t| &t.value) {
+                Some(TokenKind::Opcode(name)) if self.is_macro(name) => Some(pos),
+                _ => None,
+            },
+        };
+        let call = match call {
+            Some(call) if active => call,
+            _ => {
+                out.line(tokens);
+                return;
+            }
+        };
+        if call == 1 {
+            out.line(label_line(&tokens));
+        }
+        let name = label_name(stmt[call]);
+        // The arguments are cut from `text`, so use the columns of `raw`.
+        let raw_stmt: Vec<&Token> = raw
+            .iter()
+            .filter(|t| !matches!(t.value, TokenKind::Comment(_)))
+            .collect();
+        let end = raw
+            .iter()
+            .find(|t| matches!(t.value, TokenKind::Comment(_)))
+            .map_or(usize::MAX, |t| t.loc.start);
+        let args_text: String = text
+            .chars()
+            .take(end)
+            .skip(raw_stmt[call].loc.end)
+            .collect();
+        let args = split_args(&args_text);
+        let call_loc = stmt[call].loc.merge(&stmt[stmt.len() - 1].loc);
+        if depth >= MAX_EXPANSION_DEPTH {
+            out.error(
+                Diagnostic::error(format!(
+                    "macro `{}` is nested more than {} levels deep",
+                    name, MAX_EXPANSION_DEPTH
+                ))
+                .with_loc(call_loc)
+                .with_note("a macro probably invokes itself unconditionally"),
+            );
+            self.runaway = true;
+            return;
+        }
+        let unique = self.invocations;
+        self.invocations += 1;
+        let body = self.macros[&name].body.clone();
+        let expansion = Some(Box::new(Expansion {
+            name,
+            call: call_loc,
+        }));
+        for body_line in body {
+            if self.runaway {
+                break;
+            }
+            let (text, columns) = substitute(&body_line.text, &args, unique);
+            let loc = Loc {
+                expansion: expansion.clone(),
+                ..Loc::new(body_line.file, body_line.line, 0, 0)
+            };
+            self.process(&text, &loc, Some(&columns), depth + 1, out, includer);
+        }
+    }
+
+    fn include(
+        &mut self,
+        name: &str,
+        loc: &Loc,
+        depth: usize,
+        out: &mut dyn LineSink,
+        includer: &mut dyn Includer,
+    ) {
+        let (file, text) = match includer.enter(name, loc) {
+            Ok(included) => included,
+            Err(e) => {
+                out.error(e.or_loc(loc.clone()));
+                return;
+            }
+        };
+        for (i, line) in text.lines().enumerate() {
+            self.process(
+                line,
+                &Loc::new(file, i + 1, 0, 0),
+                None,
+                depth,
+                out,
+                includer,
+            );
+        }
+        includer.leave();
+    }
+
+    fn is_macro(&self, name: &[char]) -> bool {
+        self.macros.contains_key(&name.iter().collect::<String>())
+    }
+
+    fn define(&mut self, name: String, loc: Loc, out: &mut dyn LineSink) {
+        match self.macros.get(&name) {
+            Some(previous) if self.keeping => out.error(
+                Diagnostic::error(format!("macro `{}` is defined more than once", name))
+                    .with_loc(loc.clone())
+                    .with_related(
+                        Diagnostic::note("first defined here").with_loc(previous.loc.clone()),
+                    ),
+            ),
+            _ => {}
+        }
+        let def = Macro { loc, body: vec![] };
+        self.defining = Some((name, def, 0));
+    }
+
+    // Adds a line to the body of the macro being defined, or ends the
+    // definition at its `.endm`.
+    fn collect(&mut self, text: &str, line: &Loc) {
+        let (_, def, nested) = self.defining.as_mut().expect("a macro is being defined");
+        match line_directive(text) {
+            Some(Directive::MACRO) => *nested += 1,
+            Some(Directive::ENDM) if *nested == 0 => {
+                let (name, def, _) = self.defining.take().expect("a macro is being defined");
+                if self.keeping {
+                    self.macros.insert(name, def);
+                }
+                return;
+            }
+            Some(Directive::ENDM) => *nested -= 1,
+            _ => {}
+        }
+        def.body.push(BodyLine {
+            text: text.to_string(),
+            file: line.file,
+            line: line.line,
+        });
+    }
+}
+
+// The label that starts `tokens` (and the comment), as a line of its own.
+fn label_line(tokens: &[Token]) -> Vec<Token> {
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(i, t)| *i == 0 || matches!(t.value, TokenKind::Comment(_)))
+        .map(|(_, t)| t.clone())
+        .collect()
+}
+
+// `loc` in the columns of the body line that a substituted line came from.
+fn body_loc(loc: &Loc, columns: Option<&[(usize, usize)]>) -> Loc {
+    let columns = match columns {
+        Some(columns) => columns,
+        None => return loc.clone(),
+    };
+    let last = columns.len() - 1;
+    let (start, _) = columns[loc.start.min(last)];
+    let (_, end) = columns[loc.end.saturating_sub(1).min(last)];
+    Loc {
+        start,
+        end: end.max(start),
+        ..loc.clone()
+    }
+}
+
+fn directive(token: &Token) -> Option<Directive> {
+    match &token.value {
+        TokenKind::Directive(d) => Directive::from_str(&d.iter().collect::<String>()).ok(),
+        _ => None,
+    }
+}
+
+// The name in a label definition, macro name or label token, without any
+// trailing colon.
+fn label_name(token: &Token) -> String {
+    match &token.value {
+        TokenKind::LabelDef(name) | TokenKind::Label(name) | TokenKind::Opcode(name) => {
+            let name = match name.last() {
+                Some(':') => &name[..name.len() - 1],
+                _ => &name[..],
+            };
+            name.iter().collect()
+        }
+        _ => String::new(),
+    }
+}
+
+// The directive a raw line starts with, after an optional label. Macro
+// bodies are not tokenized until they are expanded, since `\1` and the like
+// are only valid then.
+fn line_directive(text: &str) -> Option<Directive> {
+    let code = text.split(';').next().unwrap_or("");
+    let mut words = code.split_whitespace();
+    let first = words.next()?;
+    let word = if first.starts_with('.') {
+        first
+    } else {
+        words.next()?
+    };
+    Directive::from_str(word).ok()
+}
+
+// Splits the arguments of an invocation at commas outside quotes and
+// parentheses. Empty arguments at the end are left out, so that passing
+// `\2, \3` on when they are empty counts as no arguments for `\#`.
+fn split_args(text: &str) -> Vec<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return vec![];
+    }
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth = depth.saturating_sub(1),
+            ',' if !quoted && depth == 0 => {
+                args.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    args.push(current.trim().to_string());
+    while args.last().is_some_and(|arg| arg.is_empty()) {
+        args.pop();
+    }
+    args
+}
+
+// Replaces `\1`-`\9` with the arguments (empty if not given), `\#` with the
+// number of arguments and `\@` with `_N` for the `unique`th invocation.
+// Also returns, for each character of the result plus one past the end, the
+// column range of `text` it came from.
+fn substitute(text: &str, args: &[String], unique: usize) -> (String, Vec<(usize, usize)>) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut columns = vec![];
+    let mut pos = 0;
+    while pos < chars.len() {
+        let replacement = match (chars[pos], chars.get(pos + 1)) {
+            ('\\', Some(&n @ '1'..='9')) => {
+                let index = n as usize - '1' as usize;
+                Some(args.get(index).cloned().unwrap_or_default())
+            }
+            ('\\', Some('#')) => Some(args.len().to_string()),
+            ('\\', Some('@')) => Some(format!("_{}", unique)),
+            _ => None,
+        };
+        match replacement {
+            Some(replacement) => {
+                for c in replacement.chars() {
+                    out.push(c);
+                    columns.push((pos, pos + 2));
+                }
+                pos += 2;
+            }
+            None => {
+                out.push(chars[pos]);
+                columns.push((pos, pos + 1));
+                pos += 1;
+            }
+        }
+    }
+    columns.push((pos, pos + 1));
+    (out, columns)
+}
@@ -1,25 +1,352 @@
-use log::debug;
+use famiasm::assembler::DEFAULT_MAX_ERRORS;
+use famiasm::symbol_file::{self, SymbolFormat};
+use famiasm::{assemble_source, Diagnostic, Options, OutputFormat};
+use log::{debug, info, LevelFilter, Log, Metadata, Record};
 use std::env;
-mod assembler;
-use assembler::Assembler;
-mod common;
-mod directive;
-mod insts;
-mod nes_header;
-mod parser;
-mod symbol_table;
-mod tokenizer;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Exit codes.
+const EXIT_SUCCESS: i32 = 0;
+// The source has errors.
+const EXIT_ASSEMBLY_ERROR: i32 = 1;
+// The command line is invalid.
+const EXIT_USAGE: i32 = 2;
+// An input could not be read or an output could not be written.
+const EXIT_IO_ERROR: i32 = 3;
+
+const USAGE: &str = "Usage: famiasm [OPTIONS] <input.asm>
+
+Options:
+  -o, --output FILE      write the ROM to FILE (default: input with .nes/.bin extension)
+  -I, --include DIR      search DIR for .include and .incbin files (may be repeated)
+  -D, --define NAME[=VALUE]
+                         define NAME as VALUE (default 1) before assembling
+      --format FORMAT    output format: nes (iNES image, default) or raw (no header)
+      --listing FILE     write an assembly listing to FILE
+      --dbg FILE         write ld65-style debug info (.dbg) for source-level debugging
+      --ram-usage FILE   write how much zero page and work RAM is reserved to FILE
+      --symbols FILE     write every symbol's bank, address and kind to FILE
+      --symbol-format FORMAT
+                         format of --symbols: txt (default), json or vice
+      --nl               write FCEUX name lists next to the ROM (ROM.N.nl, ROM.ram.nl)
+      --mlb              write a Mesen label file next to the ROM (.mlb)
+      --max-errors N     show only the first N errors (default 20, 0 for no limit)
+  -q, --quiet            only print errors
+  -v, --verbose          print progress (repeat for debug output)
+  -h, --help             print this help
+  -V, --version          print the version
+
+Exit status: 0 on success, 1 if the source has errors, 2 on invalid usage,
+3 if a file cannot be read or written.";
+
+// Prints log records to stderr as `level: message`.
+struct StderrLogger;
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{}: {}",
+                record.level().to_string().to_lowercase(),
+                record.args()
+            );
+        }
+    }
+    fn flush(&self) {}
+}
+static LOGGER: StderrLogger = StderrLogger;
+
+struct Cli {
+    input: String,
+    output: Option<String>,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, u16)>,
+    format: OutputFormat,
+    listing: Option<String>,
+    dbg: Option<String>,
+    ram_usage: Option<String>,
+    symbols: Option<String>,
+    symbol_format: SymbolFormat,
+    nl: bool,
+    mlb: bool,
+    max_errors: usize,
+    // -1 for --quiet, 0 by default, one more for each --verbose.
+    verbosity: i32,
+}
+
+enum Command {
+    Assemble(Box<Cli>),
+    Help,
+    Version,
+}
+
+// Takes the value of an option given as `--opt VALUE`, `--opt=VALUE` or,
+// for short options, `-oVALUE`.
+fn option_value<'a>(
+    arg: &'a str,
+    name: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+) -> Result<&'a str, String> {
+    let rest = &arg[name.len()..];
+    if let Some(value) = rest.strip_prefix('=') {
+        return Ok(value);
+    }
+    if !rest.is_empty() {
+        return Ok(rest);
+    }
+    iter.next()
+        .map(|s| s.as_str())
+        .ok_or_else(|| format!("`{}` requires a value", name))
+}
+
+// Parses `$FF`, `0xFF`, `%1010` or decimal.
+fn parse_number(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix('%') {
+        u16::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+// Parses the `NAME[=VALUE]` argument of `-D`.
+fn parse_define(arg: &str) -> Result<(String, u16), String> {
+    let (name, value) = match arg.find('=') {
+        Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+        None => (arg, None),
+    };
+    let is_name = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_name {
+        return Err(format!("invalid symbol name `{}` for `-D`", name));
+    }
+    let value = match value {
+        Some(value) => parse_number(value)
+            .ok_or_else(|| format!("invalid value `{}` for `-D {}`", value, name))?,
+        None => 1,
+    };
+    Ok((name.to_string(), value))
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut input = None;
+    let mut output = None;
+    let mut include_dirs = vec![];
+    let mut defines = vec![];
+    let mut format = OutputFormat::Nes;
+    let mut listing = None;
+    let mut dbg = None;
+    let mut ram_usage = None;
+    let mut symbols = None;
+    let mut symbol_format = SymbolFormat::Text;
+    let mut nl = false;
+    let mut mlb = false;
+    let mut max_errors = DEFAULT_MAX_ERRORS;
+    let mut verbosity = 0;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let name = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => &arg[..i],
+            _ => arg.as_str(),
+        };
+        match name {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-q" | "--quiet" => verbosity = -1,
+            "--verbose" => verbosity += 1,
+            "--output" => output = Some(option_value(arg, name, &mut iter)?.to_string()),
+            "--include" => include_dirs.push(PathBuf::from(option_value(arg, name, &mut iter)?)),
+            "--define" => defines.push(parse_define(option_value(arg, name, &mut iter)?)?),
+            "--format" => {
+                let value = option_value(arg, name, &mut iter)?;
+                format = value
+                    .parse()
+                    .map_err(|_| format!("unknown output format `{}`", value))?;
+            }
+            "--symbol-format" => {
+                let value = option_value(arg, name, &mut iter)?;
+                symbol_format = value
+                    .parse()
+                    .map_err(|_| format!("unknown symbol format `{}`", value))?;
+            }
+            "--nl" => nl = true,
+            "--mlb" => mlb = true,
+            "--listing" => listing = Some(option_value(arg, name, &mut iter)?.to_string()),
+            "--dbg" => dbg = Some(option_value(arg, name, &mut iter)?.to_string()),
+            "--ram-usage" => ram_usage = Some(option_value(arg, name, &mut iter)?.to_string()),
+            "--symbols" => symbols = Some(option_value(arg, name, &mut iter)?.to_string()),
+            "--max-errors" => {
+                let value = option_value(arg, name, &mut iter)?;
+                max_errors = value
+                    .parse()
+                    .map_err(|_| format!("invalid value `{}` for `--max-errors`", value))?;
+            }
+            _ if arg.starts_with("-o") => {
+                output = Some(option_value(arg, "-o", &mut iter)?.to_string())
+            }
+            _ if arg.starts_with("-I") => {
+                include_dirs.push(PathBuf::from(option_value(arg, "-I", &mut iter)?))
+            }
+            _ if arg.starts_with("-D") => {
+                defines.push(parse_define(option_value(arg, "-D", &mut iter)?)?)
+            }
+            _ if arg.starts_with("-v") && arg[1..].chars().all(|c| c == 'v') => {
+                verbosity += arg.len() as i32 - 1
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let input = input.ok_or_else(|| "no input file".to_string())?;
+    Ok(Command::Assemble(Box::new(Cli {
+        input,
+        output,
+        include_dirs,
+        defines,
+        format,
+        listing,
+        dbg,
+        ram_usage,
+        symbols,
+        symbol_format,
+        nl,
+        mlb,
+        max_errors,
+        verbosity,
+    })))
+}
+
+fn write_file(path: &str, data: &[u8]) -> Result<(), i32> {
+    fs::write(path, data).map_err(|e| {
+        eprintln!(
+            "{}",
+            Diagnostic::error(format!("cannot write `{}`: {}", path, e))
+        );
+        EXIT_IO_ERROR
+    })?;
+    info!("wrote {} bytes to {}", data.len(), path);
+    Ok(())
+}
+
+fn report_errors(errors: &[Diagnostic], max_errors: usize) {
+    for e in errors {
+        eprintln!("{}", e);
+    }
+    if max_errors != 0 && errors.len() >= max_errors {
+        eprintln!(
+            "error: aborting after {} errors (raise the limit with --max-errors)",
+            errors.len()
+        );
+    } else if errors.len() == 1 {
+        eprintln!("error: aborting due to previous error");
+    } else {
+        eprintln!("error: aborting due to {} previous errors", errors.len());
+    }
+}
+
+fn run(cli: Cli) -> Result<(), i32> {
+    let output_path = cli.output.clone().unwrap_or_else(|| {
+        Path::new(&cli.input)
+            .with_extension(cli.format.extension())
+            .to_string_lossy()
+            .into_owned()
+    });
+    let options = Options {
+        file_name: cli.input.clone(),
+        max_errors: cli.max_errors,
+        defines: cli.defines,
+        listing: cli.listing.is_some(),
+        debug_info: cli.dbg.is_some(),
+        ram_usage: cli.ram_usage.is_some(),
+        search_paths: cli.include_dirs,
+        ..Default::default()
+    };
+    let source = options
+        .loader
+        .load(Path::new(&cli.input))
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .map_err(|e| {
+            eprintln!(
+                "{}",
+                Diagnostic::error(format!("cannot read `{}`: {}", options.file_name, e))
+            );
+            EXIT_IO_ERROR
+        })?;
+    info!("assembling {}", cli.input);
+    let output = assemble_source(&source, &options).map_err(|errors| {
+        report_errors(&errors, options.max_errors);
+        EXIT_ASSEMBLY_ERROR
+    })?;
+    if cli.verbosity >= 0 {
+        for w in &output.warnings {
+            eprintln!("{}", w);
+        }
+    }
+    write_file(&output_path, output.image(cli.format))?;
+    if let (Some(path), Some(listing)) = (&cli.listing, &output.listing) {
+        write_file(path, listing.as_bytes())?;
+    }
+    if let (Some(path), Some(debug_info)) = (&cli.dbg, &output.debug_info) {
+        write_file(path, debug_info.as_bytes())?;
+    }
+    if let (Some(path), Some(ram_usage)) = (&cli.ram_usage, &output.ram_usage) {
+        write_file(path, ram_usage.as_bytes())?;
+    }
+    if let Some(path) = &cli.symbols {
+        let text = symbol_file::symbol_map(&output.symbols, cli.symbol_format);
+        write_file(path, text.as_bytes())?;
+    }
+    if cli.nl {
+        for (suffix, text) in symbol_file::fceux_nl(&output.symbols) {
+            write_file(&format!("{}.{}", output_path, suffix), text.as_bytes())?;
+        }
+    }
+    if cli.mlb {
+        let path = Path::new(&output_path).with_extension("mlb");
+        let text = symbol_file::mesen_mlb(&output.symbols);
+        write_file(&path.to_string_lossy(), text.as_bytes())?;
+    }
+    Ok(())
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: famiasm <filename.asm>");
-        return;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match parse_args(&args) {
+        Ok(Command::Assemble(cli)) => *cli,
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            process::exit(EXIT_SUCCESS);
+        }
+        Ok(Command::Version) => {
+            println!("famiasm {}", env!("CARGO_PKG_VERSION"));
+            process::exit(EXIT_SUCCESS);
+        }
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let level = match cli.verbosity {
+        i32::MIN..=-1 => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
     }
     debug!("{:?}", args);
-    let filename = &args[1];
-    let output_filename = filename.replace(".asm", ".nes");
-
-    let mut assembler = Assembler::new();
-    assembler.assemble(filename.to_string(), output_filename);
+    if let Err(code) = run(cli) {
+        process::exit(code);
+    }
 }
@@ -1,3 +1,22 @@
+use crate::insts::RamAddress;
+
+// Where a CPU address is stored in the ROM image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RomLocation {
+    // 16kB PRG bank and offset within it.
+    Prg(usize, usize),
+    // 8kB CHR bank and offset within it.
+    Chr(usize, usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RomLocationError {
+    // The address is not one that ROM is mapped to.
+    NotInRom,
+    // The bank is past the PRG and CHR banks of the ROM.
+    NoSuchBank,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct NesHeader {
     // Number of 16kB ROM banks.
@@ -32,8 +51,44 @@ impl Default for NesHeader {
 }
 
 impl NesHeader {
-    pub fn gen_binary(&self) -> [u8; 16] {
-        let mut arr: [u8; 16] = Default::default();
+    // Length of the header in bytes.
+    pub const SIZE: usize = 16;
+    pub const PRG_BANK_SIZE: usize = 16 * 1024;
+    pub const CHR_BANK_SIZE: usize = 8 * 1024;
+
+    // Maps a bank and CPU address to the ROM bank that holds it. Banks
+    // after the PRG banks are CHR banks, addresses at $C000 and up always
+    // go to the last PRG bank, and with a single PRG bank, `.bank 1` is
+    // the first CHR bank.
+    pub fn rom_location(&self, address: &RamAddress) -> Result<RomLocation, RomLocationError> {
+        let num_prg_rom = self.prg_rom_count as usize;
+        let num_chr_rom = self.chr_rom_count as usize;
+        let bank = if num_prg_rom == 1 && address.bank != 0 {
+            address.bank - 1
+        } else {
+            address.bank
+        } as usize;
+        let index = match address.address {
+            0x0000..=0x1FFF => address.address as usize,
+            0xC000..=0xFFFF => address.address as usize - 0xC000,
+            0x8000..=0xBFFF => address.address as usize - 0x8000,
+            _ => return Err(RomLocationError::NotInRom),
+        };
+        if num_prg_rom <= bank {
+            if bank - num_prg_rom < num_chr_rom {
+                Ok(RomLocation::Chr(bank - num_prg_rom, index))
+            } else {
+                Err(RomLocationError::NoSuchBank)
+            }
+        } else if 0xC000 <= address.address {
+            Ok(RomLocation::Prg(num_prg_rom - 1, index))
+        } else {
+            Ok(RomLocation::Prg(bank, index))
+        }
+    }
+
+    pub fn gen_binary(&self) -> [u8; NesHeader::SIZE] {
+        let mut arr: [u8; NesHeader::SIZE] = Default::default();
         arr[0] = 0x4E; // N
         arr[1] = 0x45; // E
         arr[2] = 0x53; // S
@@ -53,8 +108,8 @@ impl NesHeader {
          bit 1-3   Reserved, must be zeroes!
          bit 4-7   Four higher bits of ROM Mapper Type.
         */
-        arr[7] = (self.mapper & 0xF0) | 0b00000000;
-        arr[8] = self.ram_size & 0xFF;
+        arr[7] = self.mapper & 0xF0;
+        arr[8] = self.ram_size;
         /*
          bit 0     1 for PAL cartridges, otherwise assume NTSC.
          bit 1-7   Reserved, must be zeroes!
@@ -1,16 +1,71 @@
-use crate::common::Annot;
+use crate::common::Loc;
+use crate::diagnostic::Diagnostic;
 use crate::directive::Directive;
+use crate::expr::Expr;
 use crate::insts::{
-    AbstructAddress, AbstructInstruction, Addressing, Bin, Instruction, Label, Opcode, Operand,
-    RamAddress,
+    AbstructInstruction, Addressing, Bin, Data, Instruction, Opcode, Operand, RamAddress,
 };
-use crate::nes_header::NesHeader;
-use crate::symbol_table::SymbolTable;
+use crate::loader::{self, SourceLoader};
+use crate::macros::LineSink;
+use crate::nes_header::{NesHeader, RomLocation, RomLocationError};
+use crate::ram::{self, Region, Reservation, WORK_RAM, ZERO_PAGE};
+use crate::symbol_table::{self, Label, SymbolKind, SymbolTable, Value};
 use crate::tokenizer::{Token, TokenKind};
 use log::debug;
-use std::str::FromStr;
-use std::{fs, mem};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Passes over the source before giving up on operand sizes settling.
+const MAX_PASSES: usize = 16;
+
+// An open `.if`, `.ifdef` or `.ifndef` block.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Condition {
+    // Whether the lines of the current branch are assembled.
+    active: bool,
+    // Whether a branch has been taken, or the enclosing block is skipped, so
+    // that `.else` is skipped.
+    taken: bool,
+    in_else: bool,
+    // The directive that opened the block.
+    directive: String,
+    loc: Loc,
+}
+
+// An open `.proc` or `.scope`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Scope {
+    name: String,
+    // The directive that opened it.
+    directive: String,
+    loc: Loc,
+    // The global label before the scope, which local labels belong to again
+    // after it.
+    global: Option<String>,
+}
+
+// The section that labels and statements go into.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Section {
+    // ROM, the default, selected with `.code` or `.data`.
+    Code,
+    // Zero page RAM, where only space can be reserved.
+    Zp,
+    // Work RAM, where only space can be reserved.
+    Bss,
+}
+impl Section {
+    fn name(&self) -> &'static str {
+        match self {
+            Section::Code => ".code",
+            Section::Zp => ".zp",
+            Section::Bss => ".bss",
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Parser {
@@ -19,6 +74,32 @@ pub struct Parser {
     insts: Vec<AbstructInstruction>,
     meta_info: NesHeader,
     base_path: Option<PathBuf>,
+    search_paths: Vec<PathBuf>,
+    errors: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,
+    conditions: Vec<Condition>,
+    section: Section,
+    // Location counters of the sections other than the current one.
+    code_address: RamAddress,
+    zp_address: RamAddress,
+    bss_address: RamAddress,
+    // The RAM regions that `.zp` and `.bss` reservations must stay in.
+    zp_region: Option<Region>,
+    bss_region: Option<Region>,
+    // Where the next `.rs` variable goes, and the region it must stay in.
+    rs_address: u16,
+    rs_region: Option<Region>,
+    reservations: Vec<Reservation>,
+    scopes: Vec<Scope>,
+    // The last label that is not local, which local labels belong to.
+    global_label: Option<String>,
+    // How many anonymous labels of each kind (`:`, `-`, `++`...) are defined.
+    anonymous: HashMap<String, usize>,
+    // The symbols as the previous pass left them, for sizing operands that
+    // refer to symbols defined further down.
+    previous: Option<SymbolTable>,
+    // Whether an operand was sized without knowing its value in this pass.
+    guessed: bool,
 }
 impl Parser {
     pub fn new() -> Parser {
@@ -31,9 +112,63 @@ impl Parser {
             insts: vec![],
             meta_info: Default::default(),
             base_path: None,
+            search_paths: vec![],
+            errors: vec![],
+            warnings: vec![],
+            conditions: vec![],
+            section: Section::Code,
+            code_address: RamAddress {
+                bank: 0,
+                address: 0,
+            },
+            zp_address: RamAddress {
+                bank: 0,
+                address: ZERO_PAGE.start,
+            },
+            bss_address: RamAddress {
+                bank: 0,
+                address: WORK_RAM.start,
+            },
+            zp_region: Some(ZERO_PAGE),
+            bss_region: Some(WORK_RAM),
+            rs_address: ZERO_PAGE.start,
+            rs_region: Some(ZERO_PAGE),
+            reservations: vec![],
+            scopes: vec![],
+            global_label: None,
+            anonymous: HashMap::new(),
+            previous: None,
+            guessed: false,
         }
     }
 
+    // Collects every error; the caller picks the first ones in the source to
+    // show.
+    fn report(&mut self, e: Diagnostic) {
+        self.errors.push(e);
+    }
+
+    pub fn insts(&self) -> &[AbstructInstruction] {
+        &self.insts
+    }
+
+    pub fn header(&self) -> &NesHeader {
+        &self.meta_info
+    }
+
+    // How much of zero page and work RAM `.rs` and `.ds` reserved.
+    pub fn ram_usage(&self) -> String {
+        ram::usage_report(&self.reservations, &self.symtab)
+    }
+
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symtab
+    }
+
     pub fn set_base_path(&mut self, path: &str) {
         let path = Path::new(path);
         if let Some(parent) = path.parent() {
@@ -43,572 +178,1318 @@ impl Parser {
         }
     }
 
-    fn get_operand(&self, tokens: &Vec<Annot<TokenKind>>, offset: usize) -> Option<Operand> {
-        let length = tokens.len();
-        if length <= offset {
-            return None;
+    // Predefines `name` as the constant `value`.
+    pub fn define(&mut self, name: String, value: u16) {
+        let def = Label::new(
+            SymbolKind::Constant,
+            Value::Number(i32::from(value)),
+            Loc::default(),
+        );
+        if let Err(e) = self.symtab.define(name, def) {
+            self.report(e);
         }
-        let tokens = &tokens[offset..];
-        for token in tokens {
-            match &token.value {
-                TokenKind::Im(im) => {
-                    let im: u16 = From::from(im.clone());
-                    return Some(Operand::Im(im));
-                }
-                TokenKind::Label(label) => {
-                    return Some(Operand::Address(AbstructAddress::Label(
-                        label.iter().collect::<String>(),
-                    )));
-                }
-                TokenKind::Adr8(adr) => {
-                    let adr: u16 = From::from(adr.clone());
-                    let ram_address = RamAddress {
-                        bank: self.current_address.bank,
-                        address: adr,
-                    };
-                    let adr = AbstructAddress::RamAddress(ram_address);
-                    return Some(Operand::Address(adr));
-                }
-                TokenKind::Adr16(adr) => {
-                    let adr: u16 = From::from(adr.clone());
-                    let ram_address = RamAddress {
-                        bank: self.current_address.bank,
-                        address: adr,
-                    };
-                    let adr = AbstructAddress::RamAddress(ram_address);
-                    return Some(Operand::Address(adr));
-                }
-                TokenKind::U8(val) => {
-                    return Some(Operand::U8(val.clone()));
-                }
-                TokenKind::U16(val) => {
-                    return Some(Operand::U16(val.clone()));
-                }
-                TokenKind::String(str) => {
-                    return Some(Operand::String(str.clone()));
+    }
+
+    // Directories to look in for files not found next to the source.
+    pub fn set_search_paths(&mut self, paths: Vec<PathBuf>) {
+        self.search_paths = paths;
+    }
+
+    // Reads `name` relative to the source file, falling back to the search
+    // paths in order.
+    fn load_file(&self, name: &str, loader: &dyn SourceLoader) -> Result<Vec<u8>, Diagnostic> {
+        let base = self.base_path.clone().unwrap_or_default();
+        let dirs: Vec<PathBuf> = std::iter::once(base)
+            .chain(self.search_paths.iter().cloned())
+            .collect();
+        let (_, data) = loader::find_file(loader, name, &dirs)?;
+        Ok(data)
+    }
+
+    // Evaluates an expression whose value is needed while parsing, such as
+    // the argument of `.org`. Only labels defined above can be used.
+    fn eval_now(&mut self, tokens: &[Token]) -> Result<i32, Diagnostic> {
+        let mut expr = Self::parse_expr(tokens)?;
+        self.scope_expr(&mut expr)?;
+        let value = expr.eval(&self.symtab)?;
+        self.count_references(&expr);
+        Ok(value)
+    }
+
+    fn eval_u8(&mut self, tokens: &[Token]) -> Result<u8, Diagnostic> {
+        let value = self.eval_now(tokens)?;
+        if !(0..=0xFF).contains(&value) {
+            return Err(
+                Diagnostic::error(format!("value {} does not fit in a byte", value))
+                    .with_loc(Self::span(tokens)),
+            );
+        }
+        Ok(value as u8)
+    }
+
+    fn eval_u16(&mut self, tokens: &[Token]) -> Result<u16, Diagnostic> {
+        let value = self.eval_now(tokens)?;
+        if !(0..=0xFFFF).contains(&value) {
+            return Err(Self::not_a_word(value, Self::span(tokens)));
+        }
+        Ok(value as u16)
+    }
+
+    fn not_a_word(value: i32, loc: Loc) -> Diagnostic {
+        Diagnostic::error(format!("value {} does not fit in a word", value)).with_loc(loc)
+    }
+
+    // Source range covered by `tokens`.
+    fn span(tokens: &[Token]) -> Loc {
+        match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => first.loc.merge(&last.loc),
+            _ => Loc::default(),
+        }
+    }
+
+    // Splits directive arguments at commas.
+    fn split_args(tokens: &[Token]) -> Vec<&[Token]> {
+        tokens.split(|t| t.value == TokenKind::Comma).collect()
+    }
+
+    // Whether an operand needs absolute addressing because of a 16-bit
+    // literal. Other operands are sized by their value, once any symbols in
+    // them are scoped, by `size_operand`.
+    fn is_wide(tokens: &[Token], expr: &Expr) -> bool {
+        !expr.is_byte() && tokens.iter().any(|t| matches!(t.value, TokenKind::U16(_)))
+    }
+
+    // Widens a zero page `addressing` to absolute when the value of `expr`,
+    // like `$FF+1` or a symbol defined further down, may not be in zero page.
+    fn size_operand(&mut self, addressing: Addressing, expr: &Expr) -> Addressing {
+        let wide = match addressing.widened() {
+            Some(wide) if !expr.is_byte() => wide,
+            _ => return addressing,
+        };
+        let value = match expr.eval(&self.symtab) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                // Defined further down: go by the previous pass, if any.
+                self.guessed = true;
+                self.previous
+                    .as_ref()
+                    .and_then(|previous| expr.eval(previous).ok())
+            }
+        };
+        match value {
+            Some(value) if (0..=0xFF).contains(&value) => addressing,
+            _ => wide,
+        }
+    }
+
+    // Falls back to the absolute form of a zero page `addressing` that `op`
+    // has no encoding for, like `LDA $10,Y`. Warns when the operand refers
+    // to a zero page symbol, since the instruction is then a byte longer
+    // than it looks.
+    fn promote_operand(&mut self, op: &Opcode, addressing: Addressing, expr: &Expr) -> Addressing {
+        let encodes = |addressing: &Addressing| {
+            Instruction::new(
+                op.clone(),
+                addressing.clone(),
+                None,
+                self.current_address.clone(),
+                expr.loc.clone(),
+            )
+            .get_op_info()
+            .is_some()
+        };
+        let wide = match addressing.widened() {
+            Some(wide) if !encodes(&addressing) && encodes(&wide) => wide,
+            _ => return addressing,
+        };
+        match expr.symbols().first() {
+            Some((name, _)) if !expr.is_byte() => {
+                let warning = Diagnostic::warning(format!(
+                    "`{}` does not support {} addressing; using {} addressing",
+                    op, addressing, wide
+                ))
+                .with_loc(expr.loc.clone())
+                .with_note(format!(
+                    "`{}` is in zero page, but the instruction takes an extra byte",
+                    name
+                ));
+                self.warnings.push(warning);
+            }
+            _ => (),
+        }
+        wide
+    }
+
+    // Takes the forced operand size, from an opcode suffix like `.w` and/or
+    // an operand prefix like `a:`, off the front of the operand tokens.
+    fn operand_size(tokens: &[Token]) -> Result<(Option<&Token>, &[Token]), Diagnostic> {
+        let mut size: Option<&Token> = None;
+        let mut rest = tokens;
+        while let Some((first, tail)) = rest.split_first() {
+            if !matches!(first.value, TokenKind::ForceZp | TokenKind::ForceAbs) {
+                break;
+            }
+            if let Some(previous) = size {
+                if previous.value != first.value {
+                    return Err(Diagnostic::error("conflicting operand sizes")
+                        .with_loc(first.loc.clone())
+                        .with_related(
+                            Diagnostic::note("the other size is forced here")
+                                .with_loc(previous.loc.clone()),
+                        ));
                 }
-                _ => (),
             }
+            size = Some(first);
+            rest = tail;
+        }
+        Ok((size, rest))
+    }
+
+    // Applies a forced operand size to the addressing mode of an operand.
+    fn force_size(size: &Token, addressing: Addressing) -> Result<Addressing, Diagnostic> {
+        let forced = match size.value {
+            TokenKind::ForceZp if addressing.widened().is_some() => Some(addressing.clone()),
+            TokenKind::ForceZp => addressing.narrowed(),
+            _ if addressing.narrowed().is_some() => Some(addressing.clone()),
+            _ => addressing.widened(),
+        };
+        forced.ok_or_else(|| {
+            Diagnostic::error(
+                "operand size can only be forced for zero page or absolute addressing",
+            )
+            .with_loc(size.loc.clone())
+            .with_note(format!("this instruction uses {} addressing", addressing))
+        })
+    }
+
+    fn parse_expr(tokens: &[Token]) -> Result<Expr, Diagnostic> {
+        Ok(Expr::parse(tokens)?)
+    }
+
+    fn parse_operand(tokens: &[Token]) -> Result<Operand, Diagnostic> {
+        Ok(Operand::Expr(Self::parse_expr(tokens)?))
+    }
+
+    // Determines the addressing mode of `op` from its operand tokens.
+    fn parse_addressing(
+        op: &Opcode,
+        tokens: &[Token],
+    ) -> Result<(Addressing, Option<Operand>), Diagnostic> {
+        let length = tokens.len();
+        if length == 0 {
+            return Ok((Addressing::Implied, None));
+        }
+        match op {
+            Opcode::BCC
+            | Opcode::BCS
+            | Opcode::BEQ
+            | Opcode::BMI
+            | Opcode::BNE
+            | Opcode::BPL
+            | Opcode::BVC
+            | Opcode::BVS => {
+                return Ok((Addressing::Relative, Some(Self::parse_operand(tokens)?)));
+            }
+            _ => (),
         }
-        return None;
+        let kinds: Vec<&TokenKind> = tokens.iter().map(|t| &t.value).collect();
+        Ok(match kinds.as_slice() {
+            // Accumulator op
+            [TokenKind::A] => (Addressing::Accumulator, None),
+            // Immediate op
+            [TokenKind::Hash, ..] => (
+                Addressing::Immediate,
+                Some(Self::parse_operand(&tokens[1..])?),
+            ),
+            // Indirect op
+            [TokenKind::LParen, .., TokenKind::Comma, TokenKind::X, TokenKind::RParen] => (
+                Addressing::IndirectX,
+                Some(Self::parse_operand(&tokens[1..length - 3])?),
+            ),
+            [TokenKind::LParen, .., TokenKind::RParen, TokenKind::Comma, TokenKind::Y] => (
+                Addressing::IndirectY,
+                Some(Self::parse_operand(&tokens[1..length - 3])?),
+            ),
+            [TokenKind::LParen, .., TokenKind::RParen] if *op == Opcode::JMP => (
+                Addressing::Indirect,
+                Some(Self::parse_operand(&tokens[1..length - 1])?),
+            ),
+            // Absolute|Zeropage op
+            [.., TokenKind::Comma, TokenKind::X] | [.., TokenKind::Comma, TokenKind::Y] => {
+                let operand = &tokens[..length - 2];
+                let expr = Self::parse_expr(operand)?;
+                let addressing = match (Self::is_wide(operand, &expr), kinds[length - 1]) {
+                    (false, TokenKind::X) => Addressing::ZeropageX,
+                    (false, _) => Addressing::ZeropageY,
+                    (true, TokenKind::X) => Addressing::AbsoluteX,
+                    (true, _) => Addressing::AbsoluteY,
+                };
+                (addressing, Some(Operand::Expr(expr)))
+            }
+            _ => {
+                let expr = Self::parse_expr(tokens)?;
+                let addressing = if Self::is_wide(tokens, &expr) {
+                    Addressing::Absolute
+                } else {
+                    Addressing::Zeropage
+                };
+                (addressing, Some(Operand::Expr(expr)))
+            }
+        })
     }
 
     pub fn resolve_address(&mut self) {
-        for inst in &mut self.insts {
-            if let AbstructInstruction::Instruction(inst) = inst {
-                if let Some(Operand::Address(AbstructAddress::Label(label))) = &inst.operand {
-                    if inst.addressing == Addressing::Relative {
-                        // Relative Addressing
-                        let op_info = inst.get_op_info();
-                        let mut adr = self.symtab.get(label).unwrap().clone();
-                        let rel_address: i16 = adr.address as i16
-                            - inst.address.address as i16
-                            - op_info.num_bytes as i16;
-                        adr.address = rel_address as u16;
-                        inst.operand = Some(Operand::Address(AbstructAddress::RamAddress(adr)));
-                    } else {
-                        debug!("{:?}", label);
-                        let adr = self.symtab.get(label);
-                        inst.operand = Some(Operand::Address(AbstructAddress::RamAddress(
-                            adr.unwrap().clone(),
-                        )));
-                        // Absolute Addressing
+        let mut insts = mem::take(&mut self.insts);
+        for inst in &mut insts {
+            if let AbstructInstruction::Data(data) = inst {
+                // Evaluate every value so that all errors on the line are reported.
+                let mut dat = Vec::new();
+                for expr in &data.exprs {
+                    self.count_references(expr);
+                    match self.eval_data(expr, data.size) {
+                        Ok(bytes) => dat.extend(bytes),
+                        Err(e) => self.report(e),
                     }
                 }
-            } else if let AbstructInstruction::Label(labelobj) = inst {
-                if let AbstructAddress::Label(label) = &labelobj.label {
-                    debug!("yyyyyyyyyyyyy {:?}", label);
-                    let adr = self.symtab.get(label).unwrap().clone();
-                    let bin =
-                        Bin::new(adr.address.to_le_bytes().to_vec(), labelobj.address.clone());
-                    *inst = AbstructInstruction::Bin(bin);
-                }
+                let bin = Bin::new(dat, data.address.clone(), data.loc.clone());
+                *inst = AbstructInstruction::Bin(bin);
+                continue;
+            }
+            if let AbstructInstruction::Instruction(Instruction {
+                operand: Some(Operand::Expr(expr)),
+                ..
+            }) = inst
+            {
+                self.count_references(expr);
+            }
+            if let Err(e) = self.resolve_inst(inst) {
+                self.report(e);
             }
         }
+        self.insts = insts;
     }
 
-    pub fn gen_binary(&self) -> Vec<u8> {
+    fn count_references(&mut self, expr: &Expr) {
+        for (name, scope) in expr.symbols() {
+            self.symtab.reference(name, scope);
+        }
+    }
+
+    // Symbols defined with `.set` are replaced by their value where they are
+    // used, so one that is left in `expr` is used above its first definition,
+    // where it has no value yet.
+    fn check_set_symbols(&self, expr: &Expr) -> Result<(), Diagnostic> {
+        for (name, scope) in expr.symbols() {
+            if let Some(label) = self.symtab.lookup(name, scope).filter(|l| l.redefinable) {
+                return Err(Diagnostic::error(format!(
+                    "`{}` is used before it is defined with `.set`",
+                    name
+                ))
+                .with_loc(expr.loc.clone())
+                .with_note("a `.set` symbol has the value it was last set to above its use")
+                .with_related(
+                    Diagnostic::note(format!("`{}` is set here", name)).with_loc(label.loc.clone()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_inst(&self, inst: &mut AbstructInstruction) -> Result<(), Diagnostic> {
+        if let AbstructInstruction::Instruction(inst) = inst {
+            if let Some(Operand::Expr(expr)) = &inst.operand {
+                debug!("{:?}", expr);
+                self.check_set_symbols(expr)?;
+                let value = expr.eval(&self.symtab)?;
+                let operand = match inst.addressing {
+                    Addressing::Relative => {
+                        // Relative Addressing
+                        let op_info = inst.op_info();
+                        let rel_address =
+                            value - i32::from(inst.address.address) - i32::from(op_info.num_bytes);
+                        if !(-128..=127).contains(&rel_address) {
+                            return Err(Diagnostic::error(format!(
+                                "branch target is out of range ({} bytes away)",
+                                rel_address
+                            ))
+                            .with_loc(expr.loc.clone())
+                            .with_note("branches can reach -128 to +127 bytes"));
+                        }
+                        Operand::Address(RamAddress {
+                            bank: inst.address.bank,
+                            address: rel_address as u16,
+                        })
+                    }
+                    Addressing::Immediate => {
+                        if !(-128..=255).contains(&value) {
+                            return Err(Diagnostic::error(format!(
+                                "immediate value {} does not fit in a byte",
+                                value
+                            ))
+                            .with_loc(expr.loc.clone()));
+                        }
+                        Operand::Im(value as u16 & 0xFF)
+                    }
+                    Addressing::Zeropage
+                    | Addressing::ZeropageX
+                    | Addressing::ZeropageY
+                    | Addressing::IndirectX
+                    | Addressing::IndirectY
+                        if !(0..=0xFF).contains(&value) =>
+                    {
+                        return Err(Diagnostic::error(format!(
+                            "address ${:04X} is not in zero page",
+                            value
+                        ))
+                        .with_loc(expr.loc.clone())
+                        .with_note("this addressing mode only reaches $0000-$00FF"));
+                    }
+                    // Absolute Addressing
+                    _ if !(0..=0xFFFF).contains(&value) => {
+                        return Err(Self::not_a_word(value, expr.loc.clone()));
+                    }
+                    _ => Operand::Address(RamAddress {
+                        bank: inst.address.bank,
+                        address: value as u16,
+                    }),
+                };
+                inst.operand = Some(operand);
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluates one `.db`/`.dw` value into `size` little endian bytes.
+    fn eval_data(&self, expr: &Expr, size: u8) -> Result<Vec<u8>, Diagnostic> {
+        self.check_set_symbols(expr)?;
+        let value = expr.eval(&self.symtab)?;
+        if size == 1 && !(-128..=255).contains(&value) {
+            return Err(
+                Diagnostic::error(format!("value {} does not fit in a byte", value))
+                    .with_loc(expr.loc.clone()),
+            );
+        }
+        if size == 2 && !(-32768..=0xFFFF).contains(&value) {
+            return Err(Self::not_a_word(value, expr.loc.clone()));
+        }
+        let bytes = (value as u16).to_le_bytes();
+        Ok(bytes[..size as usize].to_vec())
+    }
+
+    pub fn gen_binary(&self) -> Result<Vec<u8>, Vec<Diagnostic>> {
         let mut nes_header: Vec<u8> = self.meta_info.gen_binary().to_vec();
         let num_prg_rom = self.meta_info.prg_rom_count as usize;
         let num_chr_rom = self.meta_info.chr_rom_count as usize;
-        let mut prg_roms: Vec<Vec<u8>> = vec![vec![0xFF; 16 * 1024]; num_prg_rom];
-        let mut chr_roms: Vec<Vec<u8>> = vec![vec![0; 8 * 1024]; num_chr_rom];
+        let mut prg_roms: Vec<Vec<u8>> = vec![vec![0xFF; NesHeader::PRG_BANK_SIZE]; num_prg_rom];
+        let mut chr_roms: Vec<Vec<u8>> = vec![vec![0; NesHeader::CHR_BANK_SIZE]; num_chr_rom];
         debug!("start ---------------------");
         debug!("nes_header = {:?}", nes_header);
         debug!("num_prg_rom = {:?}", num_prg_rom);
         debug!("num_chr_rom = {:?}", num_chr_rom);
+        let mut errors = vec![];
         for inst in &self.insts {
-            let target_bank = match inst {
+            let (address, loc, dat) = match inst {
                 AbstructInstruction::Instruction(inst) => {
-                    if num_prg_rom == 1 && inst.address.bank != 0 {
-                        inst.address.bank - 1
-                    } else {
-                        inst.address.bank
-                    }
+                    (&inst.address, &inst.loc, inst.get_inst_code())
                 }
-                AbstructInstruction::Bin(bin) => {
-                    if num_prg_rom == 1 && bin.address.bank != 0 {
-                        bin.address.bank - 1
-                    } else {
-                        bin.address.bank
-                    }
-                }
-                _ => panic!(),
-            } as usize;
-            let target_address = match inst {
-                AbstructInstruction::Instruction(inst) => inst.address.address,
-                AbstructInstruction::Bin(bin) => bin.address.address,
-                _ => panic!(),
+                AbstructInstruction::Bin(bin) => (&bin.address, &bin.loc, bin.dat.clone()),
+                AbstructInstruction::Data(_) => unreachable!("data is resolved before gen_binary"),
             };
-            let target_index = if target_address < 0x2000 {
-                target_address as usize
-            } else if 0xC000 <= target_address {
-                target_address as usize - 0xC000
-            } else if 0x8000 <= target_address {
-                target_address as usize - 0x8000
-            } else {
-                panic!();
-            } as usize;
-            let target_rom = if num_prg_rom <= target_bank as usize {
-                &mut chr_roms[target_bank - num_prg_rom]
-            } else if 0xC000 <= target_address {
-                &mut prg_roms[num_prg_rom - 1]
-            } else {
-                &mut prg_roms[target_bank]
-            };
-            // debug!("{:?}", inst);
-            // debug!("target_bank {:?}", target_bank);
-            // debug!("target_address {:?}", target_address);
-            // debug!("target_index {:?}", target_index);
-            match inst {
-                AbstructInstruction::Instruction(inst) => {
-                    let inst_code = inst.get_inst_code();
-                    target_rom[target_index..target_index + inst_code.len()]
-                        .copy_from_slice(&inst_code);
+            let target_address = address.address;
+            let (target_rom, target_index) = match self.meta_info.rom_location(address) {
+                Ok(RomLocation::Prg(bank, index)) => (&mut prg_roms[bank], index),
+                Ok(RomLocation::Chr(bank, index)) => (&mut chr_roms[bank], index),
+                Err(RomLocationError::NotInRom) => {
+                    errors.push(
+                        Diagnostic::error(format!("address ${:04X} is not in ROM", target_address))
+                            .with_loc(loc.clone())
+                            .with_note(
+                                "code and data must be placed at $8000-$FFFF (or below $2000 in a CHR bank)",
+                            ),
+                    );
+                    continue;
                 }
-                AbstructInstruction::Bin(bin) => {
-                    let len = bin.dat.len();
-                    target_rom[target_index..target_index + len].copy_from_slice(&bin.dat);
+                Err(RomLocationError::NoSuchBank) => {
+                    errors.push(
+                        Diagnostic::error(format!("bank {} does not exist", address.bank))
+                            .with_loc(loc.clone())
+                            .with_note(format!(
+                                "the ROM has {} PRG and {} CHR banks (see .inesprg/.ineschr)",
+                                num_prg_rom, num_chr_rom
+                            )),
+                    );
+                    continue;
                 }
-                _ => panic!(),
+            };
+            if target_rom.len() < target_index + dat.len() {
+                errors.push(
+                    Diagnostic::error(format!(
+                        "{} bytes at ${:04X} overflow bank {}",
+                        dat.len(),
+                        target_address,
+                        address.bank
+                    ))
+                    .with_loc(loc.clone()),
+                );
+                continue;
             }
+            target_rom[target_index..target_index + dat.len()].copy_from_slice(&dat);
         }
         // concatenate
         nes_header.extend(prg_roms.into_iter().flatten());
         nes_header.extend(chr_roms.into_iter().flatten());
-        return nes_header;
-    }
-
-    pub fn parse(&mut self, token_lines: Vec<Vec<Token>>) {
-        debug!("parse");
-        // let mut current_bank = 0;
-        // todo .org処理
-        // let mut current_address = 0;
-        for tokens in token_lines {
-            debug!("{:?}", &tokens);
-            let address = RamAddress {
-                bank: self.current_address.bank,
-                address: self.current_address.address,
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(nes_header)
+    }
+
+    // Parses every line, recovering at the next line after an error, and
+    // returns all errors found. The source is assembled once more for every
+    // pass in which an operand's size had to be guessed, until every symbol
+    // keeps its value. `source` hands every line of the program to the sink,
+    // expanding macros and `.include`s, and is called once for every pass.
+    pub fn parse(
+        &mut self,
+        source: &mut dyn FnMut(&mut dyn LineSink),
+        loader: &dyn SourceLoader,
+    ) -> Result<(), Vec<Diagnostic>> {
+        let initial = self.clone();
+        for pass in 1..=MAX_PASSES {
+            debug!("pass {}", pass);
+            self.parse_pass(source, loader);
+            let settled = !self.guessed
+                || self
+                    .previous
+                    .as_ref()
+                    .is_some_and(|previous| previous.same_values(&self.symtab));
+            // Only a settled pass reports errors: a guessed size can pick a
+            // form the instruction does not have, like `STX var,Y` before
+            // `var` is known to be in zero page.
+            if settled {
+                debug!("{:?}", &self.symtab);
+                self.resolve_address();
+                if !self.errors.is_empty() {
+                    return Err(mem::take(&mut self.errors));
+                }
+                return Ok(());
+            }
+            if pass == MAX_PASSES {
+                break;
+            }
+            let symbols = mem::replace(self, initial.clone()).symtab;
+            self.previous = Some(symbols);
+        }
+        // Errors from the last pass may come from guessed sizes, so they are
+        // not reported.
+        Err(vec![Diagnostic::error(format!(
+            "instruction sizes did not settle after {} passes",
+            MAX_PASSES
+        ))
+        .with_note(
+            "an operand's size changes a symbol that decides the operand's size; use an absolute operand to break the cycle",
+        )])
+    }
+
+    fn parse_pass(&mut self, source: &mut dyn FnMut(&mut dyn LineSink), loader: &dyn SourceLoader) {
+        source(&mut Pass {
+            parser: self,
+            loader,
+        });
+        for condition in mem::take(&mut self.conditions) {
+            self.report(
+                Diagnostic::error(format!(
+                    "`{}` has no matching `.endif`",
+                    condition.directive
+                ))
+                .with_loc(condition.loc),
+            );
+        }
+        for scope in mem::take(&mut self.scopes) {
+            let closer = if scope.directive.eq_ignore_ascii_case(".proc") {
+                ".endproc"
+            } else {
+                ".endscope"
             };
-            let tokens: Vec<Token> = tokens
-                .into_iter()
-                .filter(|t| {
-                    mem::discriminant(&t.value)
-                        != mem::discriminant(&TokenKind::Comment("".chars().collect()))
-                })
-                .collect();
-            let mut token_length = tokens.len();
-            if token_length == 0 {
-                continue;
+            self.report(
+                Diagnostic::error(format!(
+                    "`{}` has no matching `{}`",
+                    scope.directive, closer
+                ))
+                .with_loc(scope.loc),
+            );
+        }
+    }
+
+    // Assembles one expanded line, or only tracks conditionals while lines
+    // are skipped.
+    fn parse_tokens(&mut self, tokens: Vec<Token>, loader: &dyn SourceLoader) {
+        let mut comment = None;
+        let tokens: Vec<Token> = tokens
+            .into_iter()
+            .filter(|t| match &t.value {
+                TokenKind::Comment(text) => {
+                    comment = Some(text.iter().collect::<String>().trim().to_string());
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        let comment = comment.filter(|c| !c.is_empty());
+        let loc = Self::span(&tokens);
+        match self.parse_conditional(&tokens) {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => {
+                self.report(e.or_loc(loc));
+                return;
             }
-            let mut current_pos = 0;
-            if let TokenKind::LabelDef(label) = &tokens[current_pos].value {
-                // tood 同一行のop処理
-                debug!("labelDef({:?})", label);
-                let label = &label[..label.len() - 1];
-                self.symtab
-                    .insert(label.iter().collect::<String>(), address.clone());
-                current_pos += 1;
-                token_length -= 1;
+        }
+        if !self.is_active() {
+            return;
+        }
+        if let Err(e) = self.parse_line(tokens, comment, loader) {
+            self.report(e.or_loc(loc));
+        }
+    }
+
+    // Whether lines are assembled, rather than skipped by a conditional.
+    fn is_active(&self) -> bool {
+        self.conditions.last().is_none_or(|c| c.active)
+    }
+
+    // Handles `.if`, `.ifdef`, `.ifndef`, `.else` and `.endif`, after an
+    // optional label. Returns whether the line was one of them.
+    fn parse_conditional(&mut self, tokens: &[Token]) -> Result<bool, Diagnostic> {
+        let pos = match tokens.first().map(|t| &t.value) {
+            Some(TokenKind::LabelDef(_)) => 1,
+            _ => 0,
+        };
+        let head = match tokens.get(pos) {
+            Some(head) => head,
+            None => return Ok(false),
+        };
+        let directive_str = match &head.value {
+            TokenKind::Directive(d) => d.iter().collect::<String>(),
+            _ => return Ok(false),
+        };
+        let d = match Directive::from_str(&directive_str) {
+            Ok(d) if d.is_conditional() => d,
+            _ => return Ok(false),
+        };
+        if pos == 1 && self.is_active() {
+            self.define_label(&tokens[0], None)?;
+        }
+        let args = &tokens[pos + 1..];
+        match d {
+            Directive::IF | Directive::IFDEF | Directive::IFNDEF => {
+                // Nothing in a skipped block is evaluated.
+                let enclosing_active = self.is_active();
+                self.conditions.push(Condition {
+                    active: false,
+                    taken: true,
+                    in_else: false,
+                    directive: directive_str.clone(),
+                    loc: head.loc.clone(),
+                });
+                if enclosing_active {
+                    // The block is already open, so an error here still
+                    // pairs with its `.endif`.
+                    let value = self.eval_condition(d, &directive_str, head, args)?;
+                    let condition = self.conditions.last_mut().expect("just pushed");
+                    condition.active = value;
+                    condition.taken = value;
+                }
             }
-            if token_length == 0 {
-                continue;
+            Directive::ELSE | Directive::ENDIF => {
+                let no_if = || {
+                    Diagnostic::error(format!("`{}` without a matching `.if`", directive_str))
+                        .with_loc(head.loc.clone())
+                };
+                if d == Directive::ENDIF {
+                    self.conditions.pop().ok_or_else(no_if)?;
+                } else {
+                    let condition = self.conditions.last_mut().ok_or_else(no_if)?;
+                    if condition.in_else {
+                        return Err(Diagnostic::error("`.else` after `.else`")
+                            .with_loc(head.loc.clone())
+                            .with_note(format!(
+                                "the block was opened by `{}` on line {}",
+                                condition.directive, condition.loc.line
+                            )));
+                    }
+                    condition.in_else = true;
+                    condition.active = !condition.taken;
+                    condition.taken = true;
+                }
+                if !args.is_empty() {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` takes no arguments",
+                        directive_str
+                    ))
+                    .with_loc(Self::span(args)));
+                }
             }
-            match &tokens[current_pos].value {
-                TokenKind::Directive(directive) => {
-                    let d = Directive::from_str(&(directive.iter().collect::<String>())).unwrap();
-                    current_pos += 1;
-                    let val = self.get_operand(&tokens, current_pos).unwrap();
-                    match d {
-                        Directive::ORG => {
-                            if let Operand::U16(adr) = val {
-                                self.current_address.address = adr;
-                                continue;
-                            } else {
-                                panic!();
-                            }
-                        }
-                        Directive::INESPRG => {
-                            if let Operand::U8(val) = val {
-                                self.meta_info.prg_rom_count = val;
-                                continue;
-                            } else {
-                                panic!();
-                            }
-                        }
-                        Directive::INESCHR => {
-                            debug!("directive({:?})", d);
-                            if let Operand::U8(val) = val {
-                                self.meta_info.chr_rom_count = val;
-                                continue;
-                            } else {
-                                panic!();
-                            }
-                        }
-                        Directive::INESMIR => {
-                            debug!("directive({:?})", d);
-                            if let Operand::U8(val) = val {
-                                self.meta_info.mirror = val;
-                                continue;
-                            } else {
-                                panic!();
-                            }
-                        }
-                        Directive::INESMAP => {
-                            debug!("directive({:?})", d);
-                            if let Operand::U8(val) = val {
-                                self.meta_info.mapper = val;
-                                continue;
-                            } else {
-                                panic!();
-                            }
-                        }
-                        Directive::BANK => {
-                            debug!("directive({:?})", d);
-                            if let Operand::U8(bank) = val {
-                                self.current_address.bank = bank;
-                                continue;
-                            } else {
-                                panic!();
-                            }
-                        }
-                        Directive::DB | Directive::BYTE => {
-                            debug!("directive({:?})", d);
-                            
-                            // Collect all remaining U8/Adr8 tokens from this line
-                            let mut bytes = Vec::new();
-                            let mut pos = current_pos;
-                            while pos < tokens.len() {
-                                match &tokens[pos].value {
-                                    TokenKind::U8(val) => {
-                                        bytes.push(*val);
-                                        pos += 1;
-                                    }
-                                    TokenKind::Adr8(val) => {
-                                        bytes.push(*val);
-                                        pos += 1;
-                                    }
-                                    _ => {
-                                        break;
-                                    }
-                                }
-                            }
-                            
-                            if bytes.is_empty() {
-                                panic!("DB/BYTE directive requires U8 values");
-                            }
-                            
-                            let bytes_len = bytes.len();
-                            let bin = Bin::new(
-                                bytes,
-                                RamAddress {
-                                    bank: self.current_address.bank,
-                                    address: self.current_address.address,
-                                },
-                            );
-                            self.insts.push(AbstructInstruction::Bin(bin));
-                            self.current_address.address += bytes_len as u16;
-                            continue;
-                        }
-                        Directive::DW | Directive::WORD => {
-                            debug!("directive({:?})", d);
-                            match val {
-                                Operand::U16(val) => {
-                                    let val = val.to_le_bytes();
-                                    let bin = Bin::new(
-                                        val.iter().cloned().collect(),
-                                        RamAddress {
-                                            bank: self.current_address.bank,
-                                            address: self.current_address.address,
-                                        },
-                                    );
-
-                                    self.insts.push(AbstructInstruction::Bin(bin));
-                                    self.current_address.address =
-                                        self.current_address.address.wrapping_add(2);
-                                    continue;
-                                }
-                                Operand::U8(val) => {
-                                    let val: u16 = From::from(val);
-                                    let val = val.to_le_bytes();
-                                    let bin = Bin::new(
-                                        val.iter().cloned().collect(),
-                                        RamAddress {
-                                            bank: self.current_address.bank,
-                                            address: self.current_address.address,
-                                        },
-                                    );
-                                    self.insts.push(AbstructInstruction::Bin(bin));
-                                    self.current_address.address =
-                                        self.current_address.address.wrapping_add(2);
-                                    continue;
-                                }
-                                Operand::Address(adr) => {
-                                    debug!("zzzzzzzz adr = {:?}", adr);
-                                    let label = Label::new(
-                                        adr,
-                                        RamAddress {
-                                            bank: self.current_address.bank,
-                                            address: self.current_address.address,
-                                        },
-                                    );
-                                    self.insts.push(AbstructInstruction::Label(label));
-                                    self.current_address.address =
-                                        self.current_address.address.wrapping_add(2);
-                                    continue;
-                                }
-                                _ => {
-                                    debug!("aa {:?}", val);
-                                    panic!();
-                                }
-                            }
-                        }
-                        Directive::INCBIN => {
-                            debug!("directive({:?})", d);
-                            let val = self.get_operand(&tokens, current_pos);
-                            if let Some(Operand::String(filename)) = val {
-                                debug!("filename({:?})", filename);
-                                let filename_str: String = filename.iter().collect();
-                                
-                                // Resolve the path relative to the ASM file
-                                let file_path = if let Some(ref base) = self.base_path {
-                                    base.join(&filename_str)
-                                } else {
-                                    PathBuf::from(&filename_str)
-                                };
-                                
-                                let data: Vec<u8> = fs::read(&file_path)
-                                    .unwrap_or_else(|e| panic!("Failed to read file {:?}: {}", file_path, e));
-                                let file_size = data.len() as u16;
-                                let bin = Bin::new(
-                                    data,
-                                    RamAddress {
-                                        bank: self.current_address.bank,
-                                        address: self.current_address.address,
-                                    },
-                                );
-                                self.insts.push(AbstructInstruction::Bin(bin));
-                                self.current_address.address += file_size;
-                            } else {
-                                panic!();
-                            }
-                            continue;
-                        }
+            _ => unreachable!("not a conditional directive"),
+        }
+        Ok(true)
+    }
+
+    // The value of the condition of an `.if`, `.ifdef` or `.ifndef`.
+    fn eval_condition(
+        &mut self,
+        d: Directive,
+        directive_str: &str,
+        head: &Token,
+        args: &[Token],
+    ) -> Result<bool, Diagnostic> {
+        if args.is_empty() {
+            return Err(
+                Diagnostic::error(format!("`{}` requires an argument", directive_str))
+                    .with_loc(head.loc.clone()),
+            );
+        }
+        match (d, args) {
+            (Directive::IF, _) => Ok(self.eval_now(args)? != 0),
+            (
+                _,
+                [Token {
+                    value: TokenKind::Label(name),
+                    ..
+                }],
+            ) => {
+                let mut expr = Expr::symbol(name.iter().collect(), Loc::default());
+                self.scope_expr(&mut expr)?;
+                let defined = expr.eval(&self.symtab).is_ok();
+                Ok(defined == (d == Directive::IFDEF))
+            }
+            _ => Err(
+                Diagnostic::error(format!("`{}` expects a symbol name", directive_str))
+                    .with_loc(Self::span(args)),
+            ),
+        }
+    }
+
+    // Handles `NAME = value`, `NAME .equ value` and `NAME .set value`.
+    // Returns whether the line was one of them.
+    fn parse_constant(
+        &mut self,
+        tokens: &[Token],
+        comment: &Option<String>,
+    ) -> Result<bool, Diagnostic> {
+        let (name, redefinable) = match tokens {
+            [Token {
+                value: TokenKind::LabelDef(name),
+                ..
+            }, Token {
+                value: TokenKind::Directive(d),
+                ..
+            }, ..] => match Directive::from_str(&d.iter().collect::<String>()) {
+                Ok(Directive::EQU) => (name, false),
+                Ok(Directive::SET) => (name, true),
+                _ => return Ok(false),
+            },
+            [Token {
+                value: TokenKind::LabelDef(name),
+                ..
+            }, Token {
+                value: TokenKind::Eq,
+                ..
+            }, ..]
+            | [Token {
+                value: TokenKind::Opcode(name),
+                ..
+            }, Token {
+                value: TokenKind::Eq,
+                ..
+            }, ..] => (name, false),
+            _ => return Ok(false),
+        };
+        let name: String = match name.last() {
+            Some(':') => name[..name.len() - 1].iter().collect(),
+            _ => name.iter().collect(),
+        };
+        let name_loc = tokens[0].loc.clone();
+        let args = &tokens[2..];
+        if args.is_empty() {
+            return Err(Diagnostic::error(format!("`{}` requires a value", name))
+                .with_loc(tokens[0].loc.merge(&tokens[1].loc)));
+        }
+        // Negative constants are kept as they are, for use as offsets.
+        let value = self.eval_now(args)?;
+        if !(-32768..=0xFFFF).contains(&value) {
+            return Err(Self::not_a_word(value, Self::span(args)));
+        }
+        let value = Value::Number(value);
+        let mut def =
+            Label::new(SymbolKind::Constant, value, name_loc).with_comment(comment.clone());
+        if redefinable {
+            def = def.redefinable();
+        }
+        self.define_symbol(&name, def)?;
+        Ok(true)
+    }
+
+    // Handles `name .rs size`, which defines `name` as the next `size` bytes
+    // at the `.rsset` counter. Returns whether the line was one.
+    fn parse_reservation(&mut self, tokens: &[Token]) -> Result<bool, Diagnostic> {
+        let name = match tokens {
+            [Token {
+                value: TokenKind::LabelDef(name),
+                ..
+            }, Token {
+                value: TokenKind::Directive(d),
+                ..
+            }, ..]
+                if Directive::from_str(&d.iter().collect::<String>()) == Ok(Directive::RS) =>
+            {
+                name
+            }
+            _ => return Ok(false),
+        };
+        let name: String = match name.last() {
+            Some(':') => name[..name.len() - 1].iter().collect(),
+            _ => name.iter().collect(),
+        };
+        let args = &tokens[2..];
+        if args.is_empty() {
+            return Err(
+                Diagnostic::error("`.rs` requires an argument").with_loc(tokens[1].loc.clone())
+            );
+        }
+        let address = RamAddress {
+            bank: 0,
+            address: self.rs_address,
+        };
+        let size = self.eval_u16(args)?;
+        let def = Label::new(
+            SymbolKind::Variable,
+            Value::Address(address),
+            tokens[0].loc.clone(),
+        );
+        self.define_symbol(&name, def)?;
+        self.reserve_rs(size, Self::span(tokens))?;
+        Ok(true)
+    }
+
+    // Reserves `size` bytes at the `.rsset` counter.
+    fn reserve_rs(&mut self, size: u16, loc: Loc) -> Result<(), Diagnostic> {
+        let address = self.rs_address;
+        self.rs_address = address.wrapping_add(size);
+        self.reserve(address, size, self.rs_region, loc)
+    }
+
+    // Records `size` bytes at `address` as used, checking that they fit in
+    // `region` and were not reserved before.
+    fn reserve(
+        &mut self,
+        address: u16,
+        size: u16,
+        region: Option<Region>,
+        loc: Loc,
+    ) -> Result<(), Diagnostic> {
+        let reservation = Reservation {
+            address,
+            size,
+            loc,
+            region,
+        };
+        reservation.check(&self.reservations)?;
+        self.reservations.push(reservation);
+        Ok(())
+    }
+
+    // Makes `section` the current section, keeping the location counter of
+    // the one that is left.
+    fn switch_section(&mut self, section: Section) {
+        let address = self.current_address.clone();
+        *self.section_address(self.section) = address;
+        self.current_address = self.section_address(section).clone();
+        self.section = section;
+    }
+
+    fn section_address(&mut self, section: Section) -> &mut RamAddress {
+        match section {
+            Section::Code => &mut self.code_address,
+            Section::Zp => &mut self.zp_address,
+            Section::Bss => &mut self.bss_address,
+        }
+    }
+
+    // The region that reservations in the current section must stay in.
+    fn section_region(&mut self) -> Option<&mut Option<Region>> {
+        match self.section {
+            Section::Code => None,
+            Section::Zp => Some(&mut self.zp_region),
+            Section::Bss => Some(&mut self.bss_region),
+        }
+    }
+
+    // An error for a statement that emits bytes outside of ROM.
+    fn not_in_ram(&self, what: &str, loc: &Loc) -> Diagnostic {
+        Diagnostic::error(format!(
+            "{} cannot be used in a `{}` section",
+            what,
+            self.section.name()
+        ))
+        .with_loc(loc.clone())
+        .with_note("only space can be reserved in RAM; use `.code` to go back to ROM")
+    }
+
+    // Defines the label in `token` at the current address.
+    fn define_label(&mut self, token: &Token, comment: Option<String>) -> Result<(), Diagnostic> {
+        let label = match &token.value {
+            TokenKind::LabelDef(label) => label,
+            _ => return Ok(()),
+        };
+        debug!("labelDef({:?})", label);
+        let label = match label.last() {
+            Some(':') if label.len() > 1 => &label[..label.len() - 1],
+            _ => &label[..],
+        };
+        let address = self.current_address.clone();
+        // Labels below $8000 name RAM, unless they are in a CHR bank.
+        let in_chr = matches!(
+            self.meta_info.rom_location(&address),
+            Ok(RomLocation::Chr(..))
+        );
+        let kind = if self.section != Section::Code || (address.address < 0x8000 && !in_chr) {
+            SymbolKind::Variable
+        } else {
+            SymbolKind::Label
+        };
+        let mut def =
+            Label::new(kind, Value::Address(address), token.loc.clone()).with_comment(comment);
+        let name: String = label.iter().collect();
+        if symbol_table::is_anonymous(&name) {
+            let count = self.anonymous.entry(name.clone()).or_default();
+            let hidden = symbol_table::anonymous_name(&name, *count);
+            *count += 1;
+            def.exported = false;
+            return self.symtab.define(hidden, def);
+        }
+        let qualified = self.define_symbol(&name, def)?;
+        if !symbol_table::is_local(&name) {
+            self.global_label = Some(qualified);
+        }
+        Ok(())
+    }
+
+    // Defines `name` in the current scope, or under the current global label
+    // if it is local. Returns the name it was defined as.
+    fn define_symbol(&mut self, name: &str, mut def: Label) -> Result<String, Diagnostic> {
+        if name.contains("::") {
+            return Err(Diagnostic::error(format!(
+                "`{}` cannot be defined from outside its scope",
+                name
+            ))
+            .with_loc(def.loc.clone())
+            .with_note("define it inside `.proc` or `.scope` instead"));
+        }
+        let qualified = if symbol_table::is_local(name) {
+            def.local = true;
+            format!("{}{}", self.global_label.as_deref().unwrap_or(""), name)
+        } else if self.scopes.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}::{}", self.scope_name(), name)
+        };
+        self.symtab.define(qualified.clone(), def)?;
+        Ok(qualified)
+    }
+
+    // The current scope, like `outer::inner`, or "" at the top level.
+    fn scope_name(&self) -> String {
+        let names: Vec<&str> = self.scopes.iter().map(|s| s.name.as_str()).collect();
+        names.join("::")
+    }
+
+    // Makes `expr` refer to symbols as seen from the current scope, and to
+    // the anonymous labels its `-`, `+`, `:-` and `:+` references point at
+    // from here.
+    fn scope_expr(&self, expr: &mut Expr) -> Result<(), Diagnostic> {
+        expr.set_scope(
+            &self.scope_name(),
+            self.global_label.as_deref().unwrap_or(""),
+        );
+        let mut result = Ok(());
+        expr.rename_symbols(&mut |name, loc| {
+            if let Some(target) = self.anonymous_target(name) {
+                match target {
+                    Ok(target) => *name = target,
+                    Err(e) => result = Err(e.with_loc(loc.clone())),
+                }
+            }
+        });
+        result
+    }
+
+    // The hidden name of the anonymous label `reference` refers to. `-` and
+    // `--` are the closest label of the same name above, `+` and `++` the
+    // closest one below, and `:-`, `:--`, `:+`, `:++` count `:` labels.
+    fn anonymous_target(&self, reference: &str) -> Option<Result<String, Diagnostic>> {
+        let (kind, offset) = match reference.strip_prefix(':') {
+            Some(run) if run.starts_with('-') => (":", -(run.len() as isize)),
+            Some(run) => (":", run.len() as isize - 1),
+            None if reference.starts_with('-') => (reference, -1),
+            None => (reference, 0),
+        };
+        if !symbol_table::is_anonymous(kind) {
+            return None;
+        }
+        let defined = self.anonymous.get(kind).copied().unwrap_or(0) as isize;
+        let index = defined + offset;
+        if index < 0 {
+            return Some(Err(Diagnostic::error(format!(
+                "no anonymous label `{}` precedes `{}`",
+                kind, reference
+            ))));
+        }
+        Some(Ok(symbol_table::anonymous_name(kind, index as usize)))
+    }
+
+    // Handles `.proc`, `.scope`, `.endproc` and `.endscope`.
+    fn parse_scope(
+        &mut self,
+        d: Directive,
+        directive: &str,
+        head: &Token,
+        args: &[Token],
+    ) -> Result<(), Diagnostic> {
+        match d {
+            Directive::PROC | Directive::SCOPE => {
+                let name = match args {
+                    [Token {
+                        value: TokenKind::Label(name),
+                        ..
+                    }] if !name.contains(&':') && !name.contains(&'.') && name[0] != '@' => {
+                        name.iter().collect::<String>()
                     }
+                    _ => {
+                        return Err(Diagnostic::error(format!("`{}` expects a name", directive))
+                            .with_loc(match args.last() {
+                                Some(last) => head.loc.merge(&last.loc),
+                                None => head.loc.clone(),
+                            })
+                            .with_note(format!("example: {} update_player", directive)))
+                    }
+                };
+                let global = self.global_label.clone();
+                if d == Directive::PROC {
+                    let address = self.current_address.clone();
+                    let def = Label::new(
+                        SymbolKind::Label,
+                        Value::Address(address),
+                        args[0].loc.clone(),
+                    );
+                    self.global_label = Some(self.define_symbol(&name, def)?);
+                } else {
+                    self.global_label = None;
                 }
-                /*TokenKind::LabelDef(label) => {
-                    // tood 同一行のop処理
-                    debug!("labelDef({:?})", label);
-                    let label = &label[..label.len()-1];
-                    self.symtab
-                        .insert(label.iter().collect::<String>(), address);
-                    //     self.symtab
-                    //     .insert(label.iter().collect::<String>(), address);
-                    continue;
-                }*/
-                TokenKind::Opcode(x) => {
-                    debug!("{:?}, Opcode(x) => {:?}", token_length, x);
-                    let opcode_str = x.iter().collect::<String>();
-                    let op: Opcode = (&opcode_str).parse().unwrap();
-                    // Implied op
-                    if token_length == 1 {
-                        let inst = Instruction::new(op, Addressing::Implied, None, address);
-                        let inst_info = inst.get_op_info();
-                        debug!("ooooppppinfo {:?}", inst.get_op_info());
-                        debug!("inst {:?}", inst);
-                        self.insts.push(AbstructInstruction::Instruction(inst));
-                        self.current_address.address =
-                            self.current_address.address + (inst_info.num_bytes as u16);
-                        continue;
+                self.scopes.push(Scope {
+                    name,
+                    directive: directive.to_string(),
+                    loc: head.loc.clone(),
+                    global,
+                });
+            }
+            _ => {
+                if !args.is_empty() {
+                    return Err(
+                        Diagnostic::error(format!("`{}` takes no arguments", directive))
+                            .with_loc(Self::span(args)),
+                    );
+                }
+                let opener = if d == Directive::ENDPROC {
+                    ".proc"
+                } else {
+                    ".scope"
+                };
+                let scope = match self.scopes.pop() {
+                    Some(scope) => scope,
+                    None => {
+                        return Err(Diagnostic::error(format!(
+                            "`{}` without a matching `{}`",
+                            directive, opener
+                        ))
+                        .with_loc(head.loc.clone()))
                     }
-                    current_pos += 1;
-                    let next = &tokens[current_pos].value;
-                    // Relative op
-                    match &op {
-                        Opcode::BCC
-                        | Opcode::BCS
-                        | Opcode::BEQ
-                        | Opcode::BMI
-                        | Opcode::BNE
-                        | Opcode::BPL
-                        | Opcode::BVC
-                        | Opcode::BVS => {
-                            let operand = self.get_operand(&tokens, current_pos);
-                            let inst = Instruction::new(
-                                op,
-                                Addressing::Relative,
-                                Some(operand.unwrap()),
-                                address,
-                            );
-                            let inst_info = inst.get_op_info();
-                            self.current_address.address =
-                                self.current_address.address + (inst_info.num_bytes as u16);
-                            debug!("{:?}", next);
-                            self.insts.push(AbstructInstruction::Instruction(inst));
-                            continue;
-                        }
-                        _ => (),
+                };
+                self.global_label = scope.global.clone();
+                if !scope.directive.eq_ignore_ascii_case(opener) {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` closes `{}`",
+                        directive, scope.directive
+                    ))
+                    .with_loc(head.loc.clone())
+                    .with_related(
+                        Diagnostic::note(format!("`{}` is opened here", scope.directive))
+                            .with_loc(scope.loc),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_line(
+        &mut self,
+        tokens: Vec<Token>,
+        comment: Option<String>,
+        loader: &dyn SourceLoader,
+    ) -> Result<(), Diagnostic> {
+        debug!("{:?}", &tokens);
+        let address = RamAddress {
+            bank: self.current_address.bank,
+            address: self.current_address.address,
+        };
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        if self.parse_constant(&tokens, &comment)? || self.parse_reservation(&tokens)? {
+            return Ok(());
+        }
+        let mut current_pos = 0;
+        if let TokenKind::LabelDef(_) = &tokens[current_pos].value {
+            self.define_label(&tokens[current_pos], comment)?;
+            current_pos += 1;
+        }
+        if current_pos == tokens.len() {
+            return Ok(());
+        }
+        // The statement after any label, for anything it emits.
+        let loc = Self::span(&tokens[current_pos..]);
+        let head = &tokens[current_pos];
+        match &head.value {
+            TokenKind::Directive(directive) => {
+                let directive_str = directive.iter().collect::<String>();
+                let d = Directive::from_str(&directive_str).map_err(|_| {
+                    Diagnostic::error(format!("unknown directive `{}`", directive_str))
+                        .with_loc(head.loc.clone())
+                })?;
+                debug!("directive({:?})", d);
+                current_pos += 1;
+                let args = &tokens[current_pos..];
+                if matches!(
+                    d,
+                    Directive::PROC | Directive::ENDPROC | Directive::SCOPE | Directive::ENDSCOPE
+                ) {
+                    return self.parse_scope(d, &directive_str, head, args);
+                }
+                let section = match d {
+                    Directive::CODE | Directive::DATA => Some(Section::Code),
+                    Directive::ZP => Some(Section::Zp),
+                    Directive::BSS => Some(Section::Bss),
+                    _ => None,
+                };
+                if let Some(section) = section {
+                    if !args.is_empty() {
+                        return Err(Diagnostic::error(format!(
+                            "`{}` takes no arguments",
+                            directive_str
+                        ))
+                        .with_loc(Self::span(args)));
                     }
-                    debug!("next = {:?}", next);
-                    match next {
-                        // Accumulator op
-                        TokenKind::A => {
-                            let inst = Instruction::new(op, Addressing::Accumulator, None, address);
-                            let inst_info = inst.get_op_info();
-                            self.current_address.address =
-                                self.current_address.address + (inst_info.num_bytes as u16);
-                            debug!("{:?}", inst.get_op_info());
-                            debug!("{:?}", inst.get_inst_code());
-                            self.insts.push(AbstructInstruction::Instruction(inst));
-                            continue;
+                    self.switch_section(section);
+                    return Ok(());
+                }
+                if self.section != Section::Code
+                    && matches!(
+                        d,
+                        Directive::BANK
+                            | Directive::DB
+                            | Directive::BYTE
+                            | Directive::DW
+                            | Directive::WORD
+                            | Directive::INCBIN
+                    )
+                {
+                    return Err(self.not_in_ram(&format!("`{}`", directive_str), &head.loc));
+                }
+                if args.is_empty() {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` requires an argument",
+                        directive_str
+                    ))
+                    .with_loc(head.loc.clone()));
+                }
+                match d {
+                    Directive::ORG => {
+                        let address = self.eval_u16(args)?;
+                        self.current_address.address = address;
+                        if let Some(region) = self.section_region() {
+                            *region = ram::region_of(address);
                         }
-                        // Immediate op
-                        TokenKind::Im(_val) => {
-                            let operand = self.get_operand(&tokens, current_pos);
-                            let inst = Instruction::new(
-                                op,
-                                Addressing::Immediate,
-                                Some(operand.unwrap()),
-                                address,
-                            );
-                            let inst_info = inst.get_op_info();
-                            self.current_address.address =
-                                self.current_address.address + (inst_info.num_bytes as u16);
-                            debug!("{:?}", inst.get_op_info());
-                            debug!("{:?}", inst.get_inst_code());
-                            self.insts.push(AbstructInstruction::Instruction(inst));
-                            continue;
+                    }
+                    Directive::INESPRG => {
+                        self.meta_info.prg_rom_count = self.eval_u8(args)?;
+                    }
+                    Directive::INESCHR => {
+                        self.meta_info.chr_rom_count = self.eval_u8(args)?;
+                    }
+                    Directive::INESMIR => {
+                        self.meta_info.mirror = self.eval_u8(args)?;
+                    }
+                    Directive::INESMAP => {
+                        self.meta_info.mapper = self.eval_u8(args)?;
+                    }
+                    Directive::BANK => {
+                        self.current_address.bank = self.eval_u8(args)?;
+                    }
+                    Directive::DB | Directive::BYTE | Directive::DW | Directive::WORD => {
+                        let size = match d {
+                            Directive::DB | Directive::BYTE => 1,
+                            _ => 2,
+                        };
+                        let mut exprs = Self::split_args(args)
+                            .into_iter()
+                            .map(Self::parse_expr)
+                            .collect::<Result<Vec<Expr>, Diagnostic>>()?;
+                        for expr in &mut exprs {
+                            self.scope_expr(expr)?;
+                            expr.bind(&mut self.symtab);
                         }
-                        // Absolute|Zeropage op
-                        TokenKind::Adr8(_) | TokenKind::Adr16(_) | TokenKind::Label(_) => {
-                            let operand = self.get_operand(&tokens, current_pos);
-                            let addressing = match next {
-                                TokenKind::Adr8(_) => {
-                                    if token_length == 2 {
-                                        Addressing::Zeropage
-                                    } else {
-                                        current_pos += 1;
-                                        let next = &tokens[current_pos].value;
-                                        match next {
-                                            TokenKind::X => Addressing::ZeropageX,
-                                            TokenKind::Y => Addressing::ZeropageY,
-                                            _ => panic!(),
-                                        }
-                                    }
-                                }
-                                TokenKind::Adr16(_) | TokenKind::Label(_) => {
-                                    if token_length == 2 {
-                                        Addressing::Absolute
-                                    } else {
-                                        current_pos += 1;
-                                        let next = &tokens[current_pos].value;
-                                        debug!("next = {:?}", next);
-                                        match next {
-                                            TokenKind::X => Addressing::AbsoluteX,
-                                            TokenKind::Y => Addressing::AbsoluteY,
-                                            _ => panic!(),
-                                        }
-                                    }
-                                }
-                                _ => panic!(), // never reached here
-                            };
-                            let inst = Instruction::new(op, addressing, operand, address);
-                            let inst_info = inst.get_op_info();
-                            self.insts.push(AbstructInstruction::Instruction(inst));
+                        let len = exprs.len() as u16 * size as u16;
+                        let data = Data::new(exprs, size, address, loc);
+                        self.insts.push(AbstructInstruction::Data(data));
+                        self.current_address.address =
+                            self.current_address.address.wrapping_add(len);
+                    }
+                    Directive::INCBIN => {
+                        if let [Token {
+                            value: TokenKind::String(filename),
+                            loc: name_loc,
+                        }] = args
+                        {
+                            debug!("filename({:?})", filename);
+                            let filename_str: String = filename.iter().collect();
+                            let data = self
+                                .load_file(&filename_str, loader)
+                                .map_err(|e| e.with_loc(name_loc.clone()))?;
+                            let file_size = u16::try_from(data.len()).map_err(|_| {
+                                Diagnostic::error(format!(
+                                    "`{}` is {} bytes, more than the 64 KiB address space",
+                                    filename_str,
+                                    data.len()
+                                ))
+                                .with_loc(name_loc.clone())
+                            })?;
+                            let bin = Bin::new(data, address, loc.clone());
+                            self.insts.push(AbstructInstruction::Bin(bin));
                             self.current_address.address =
-                                self.current_address.address + (inst_info.num_bytes as u16);
+                                self.current_address.address.wrapping_add(file_size);
+                        } else {
+                            return Err(Diagnostic::error("`.incbin` expects a file name")
+                                .with_loc(Self::span(args))
+                                .with_note("example: .incbin \"graphics.chr\""));
                         }
-                        // Indirect op
-                        TokenKind::LParen => {
-                            if token_length < 4 {
-                                panic!();
-                            }
-                            current_pos += 1;
-                            let operand = self.get_operand(&tokens, current_pos);
-                            let next = &tokens[current_pos].value;
-                            let addressing = match next {
-                                TokenKind::Adr8(_) => {
-                                    current_pos += 1;
-                                    let next = &tokens[current_pos].value;
-                                    match next {
-                                        TokenKind::X => {
-                                            current_pos += 1;
-                                            let next = &tokens[current_pos].value;
-                                            if let TokenKind::RParen = next {
-                                                Addressing::IndirectX
-                                            } else {
-                                                panic!()
-                                            }
-                                        }
-                                        TokenKind::RParen => {
-                                            current_pos += 1;
-                                            let next = &tokens[current_pos].value;
-                                            if let TokenKind::Y = next {
-                                                Addressing::IndirectY
-                                            } else {
-                                                panic!()
-                                            }
-                                        }
-                                        _ => panic!(),
-                                    }
-                                }
-                                TokenKind::Adr16(_) | TokenKind::Label(_) => {
-                                    current_pos += 1;
-                                    let next = &tokens[current_pos].value;
-                                    if let TokenKind::RParen = next {
-                                        Addressing::Indirect
-                                    } else {
-                                        panic!()
-                                    }
-                                }
-                                _ => panic!(),
-                            };
-                            let inst = Instruction::new(op, addressing, operand, address);
-                            let inst_info = inst.get_op_info();
-                            self.insts.push(AbstructInstruction::Instruction(inst));
-                            self.current_address.address =
-                                self.current_address.address + (inst_info.num_bytes as u16);
+                    }
+                    Directive::RSSET => {
+                        self.rs_address = self.eval_u16(args)?;
+                        self.rs_region = ram::region_of(self.rs_address);
+                    }
+                    Directive::RS => {
+                        let size = self.eval_u16(args)?;
+                        self.reserve_rs(size, loc)?;
+                    }
+                    Directive::DS => {
+                        if self.section == Section::Code {
+                            return Err(Diagnostic::error(
+                                "`.ds` can only be used in `.zp` and `.bss` sections",
+                            )
+                            .with_loc(head.loc.clone()));
                         }
-                        _ => (),
+                        let size = self.eval_u16(args)?;
+                        self.current_address.address =
+                            self.current_address.address.wrapping_add(size);
+                        let region = self.section_region().and_then(|region| *region);
+                        self.reserve(address.address, size, region, loc)?;
+                    }
+                    Directive::CODE | Directive::DATA | Directive::ZP | Directive::BSS => {
+                        unreachable!("sections are switched above")
+                    }
+                    Directive::PROC
+                    | Directive::ENDPROC
+                    | Directive::SCOPE
+                    | Directive::ENDSCOPE => unreachable!("scopes are opened and closed above"),
+                    Directive::EQU | Directive::SET => {
+                        return Err(Diagnostic::error(format!(
+                            "`{}` must follow the name it defines",
+                            directive_str
+                        ))
+                        .with_loc(head.loc.clone())
+                        .with_note(format!("example: PPUCTRL {} $2000", directive_str)));
+                    }
+                    Directive::INCLUDE | Directive::MACRO | Directive::ENDM => {
+                        unreachable!("includes and macros are expanded before parsing")
                     }
-                    debug!("op");
-                    //                debug!("{:?}", op);
+                    Directive::IF
+                    | Directive::IFDEF
+                    | Directive::IFNDEF
+                    | Directive::ELSE
+                    | Directive::ENDIF => unreachable!("conditionals are handled in parse"),
                 }
-                _ => {
-                    continue;
+            }
+            TokenKind::Opcode(_) if self.section != Section::Code => {
+                return Err(self.not_in_ram("instructions", &head.loc));
+            }
+            TokenKind::Opcode(x) => {
+                debug!("Opcode(x) => {:?}", x);
+                let opcode_str = x.iter().collect::<String>();
+                let op: Opcode = opcode_str.parse().map_err(|_| {
+                    Diagnostic::error(format!("unknown instruction `{}`", opcode_str))
+                        .with_loc(head.loc.clone())
+                })?;
+                let (size, operand_tokens) = Self::operand_size(&tokens[current_pos + 1..])?;
+                let (mut addressing, mut operand) = Self::parse_addressing(&op, operand_tokens)?;
+                if let Some(size) = size {
+                    addressing = Self::force_size(size, addressing)?;
+                }
+                if let Some(Operand::Expr(expr)) = &mut operand {
+                    self.scope_expr(expr)?;
+                    if size.is_none() {
+                        addressing = self.size_operand(addressing, expr);
+                        addressing = self.promote_operand(&op, addressing, expr);
+                    }
+                    expr.bind(&mut self.symtab);
                 }
+                let inst = Instruction::new(op, addressing, operand, address, loc);
+                let inst_info = inst.get_op_info().ok_or_else(|| {
+                    Diagnostic::error(format!(
+                        "`{}` does not support {} addressing",
+                        inst.opcode, inst.addressing
+                    ))
+                    .with_loc(head.loc.merge(&Self::span(operand_tokens)))
+                })?;
+                debug!("inst {:?} {:?}", inst, inst_info);
+                self.insts.push(AbstructInstruction::Instruction(inst));
+                self.current_address.address = self
+                    .current_address
+                    .address
+                    .wrapping_add(inst_info.num_bytes as u16);
+            }
+            _ => {
+                return Err(Diagnostic::error("expected an instruction or directive")
+                    .with_loc(head.loc.clone()));
             }
         }
-        debug!("xxxxxxxxxxxxxxxxxxxxxxxxxx");
-        debug!("{:?}", &self.symtab);
-        // debug!("xxxxxxxxxxxxxxxxxxxxxxxxxx");
-        // for inst in &self.insts {
-        //     debug!("{:?}", inst);
-        // }
-        self.resolve_address();
-        // debug!("xxxxxxxxxxxxxxxxxxxxxxxxxx");
-        // for inst in &self.insts {
-        //     debug!("{:?}", inst);
-        // }
+        Ok(())
+    }
+}
+
+// The parser as it receives the lines of one pass from the macro expander.
+struct Pass<'a> {
+    parser: &'a mut Parser,
+    loader: &'a dyn SourceLoader,
+}
+impl LineSink for Pass<'_> {
+    fn line(&mut self, tokens: Vec<Token>) {
+        self.parser.parse_tokens(tokens, self.loader);
+    }
+    fn is_active(&self) -> bool {
+        self.parser.is_active()
+    }
+    fn error(&mut self, e: Diagnostic) {
+        self.parser.report(e);
     }
 }
@@ -0,0 +1,157 @@
+use crate::common::Loc;
+use crate::diagnostic::Diagnostic;
+use crate::symbol_table::{SymbolKind, SymbolTable};
+use std::fmt::Write;
+
+// A range of RAM that variables are reserved in, checked for overflow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u16,
+    // One past the last address.
+    pub end: u32,
+}
+
+pub const ZERO_PAGE: Region = Region {
+    name: "zero page",
+    start: 0x0000,
+    end: 0x0100,
+};
+// $0100-$01FF is the stack and $0200-$02FF is usually the OAM buffer.
+pub const WORK_RAM: Region = Region {
+    name: "work RAM",
+    start: 0x0300,
+    end: 0x0800,
+};
+const REGIONS: [Region; 2] = [ZERO_PAGE, WORK_RAM];
+
+// The region `address` is in, if any.
+pub fn region_of(address: u16) -> Option<Region> {
+    REGIONS
+        .iter()
+        .copied()
+        .find(|r| (u32::from(r.start)..r.end).contains(&u32::from(address)))
+}
+
+// Space set aside by `.rs` or `.ds`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Reservation {
+    pub address: u16,
+    pub size: u16,
+    pub loc: Loc,
+    // The region the counter was in when `.rsset`, `.zp`, `.bss` or `.org`
+    // set it, which every reservation made with it must stay in.
+    pub region: Option<Region>,
+}
+
+impl Reservation {
+    // Fails when the reservation runs past the end of its region, including
+    // when an earlier one already filled it, or shares bytes with one of
+    // `reserved`.
+    pub fn check(&self, reserved: &[Reservation]) -> Result<(), Diagnostic> {
+        self.check_region()?;
+        match reserved.iter().find(|other| self.overlaps(other)) {
+            Some(other) => Err(Diagnostic::error(format!(
+                "reserving {} bytes at ${:04X} overlaps the {} bytes reserved at ${:04X}",
+                self.size, self.address, other.size, other.address
+            ))
+            .with_loc(self.loc.clone())
+            .with_note("`.rs`, `.zp` and `.bss` each keep their own address; move one with `.rsset` or `.org`")
+            .with_related(
+                Diagnostic::note("the other reservation is here").with_loc(other.loc.clone()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn check_region(&self) -> Result<(), Diagnostic> {
+        let (start, end) = self.range();
+        let region = self.region;
+        let limit = region.map_or(0x10000, |r| r.end);
+        if end <= limit && start < limit {
+            return Ok(());
+        }
+        let e = Diagnostic::error(format!(
+            "reserving {} bytes at ${:04X} overflows {}",
+            self.size,
+            self.address,
+            region.map_or("the address space", |r| r.name)
+        ))
+        .with_loc(self.loc.clone());
+        Err(match region {
+            Some(r) => {
+                let left = limit.saturating_sub(start);
+                e.with_note(format!(
+                    "{} is ${:04X}-${:04X}; {} byte{} left",
+                    r.name,
+                    r.start,
+                    r.end - 1,
+                    left,
+                    if left == 1 { " is" } else { "s are" }
+                ))
+            }
+            None => e,
+        })
+    }
+
+    fn overlaps(&self, other: &Reservation) -> bool {
+        let (start, end) = self.range();
+        let (other_start, other_end) = other.range();
+        start < end && other_start < other_end && start < other_end && other_start < end
+    }
+
+    // The addresses it covers, end exclusive.
+    fn range(&self) -> (u32, u32) {
+        let start = u32::from(self.address);
+        (start, start + u32::from(self.size))
+    }
+}
+
+// A summary of how much of each region is reserved, followed by every
+// reservation with the variables at its address.
+pub fn usage_report(reservations: &[Reservation], symtab: &SymbolTable) -> String {
+    let mut out = String::new();
+    for region in &REGIONS {
+        // Count each byte once, however many reservations cover it.
+        let mut covered = vec![false; (region.end - u32::from(region.start)) as usize];
+        for r in reservations {
+            let (start, end) = r.range();
+            for address in start.max(u32::from(region.start))..end.min(region.end) {
+                covered[(address - u32::from(region.start)) as usize] = true;
+            }
+        }
+        let used = covered.iter().filter(|&&used| used).count() as u32;
+        let total = region.end - u32::from(region.start);
+        writeln!(
+            out,
+            "{:9} ${:04X}-${:04X} {:5} of {:5} bytes used ({}%)",
+            region.name,
+            region.start,
+            region.end - 1,
+            used,
+            total,
+            used * 100 / total
+        )
+        .unwrap();
+    }
+    let mut sorted: Vec<&Reservation> = reservations.iter().collect();
+    sorted.sort_by_key(|r| r.address);
+    if !sorted.is_empty() {
+        writeln!(out).unwrap();
+    }
+    for r in sorted {
+        let mut names: Vec<&String> = symtab
+            .iter()
+            .filter(|(_, label)| {
+                label.kind == SymbolKind::Variable
+                    && label.value.address().map(|a| a.address) == Some(r.address)
+            })
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let line = format!("${:04X} {:5} {}", r.address, r.size, names.join(", "));
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+    out
+}
@@ -0,0 +1,167 @@
+use crate::assembler::Symbol;
+use crate::nes_header::NesHeader;
+use crate::symbol_table::SymbolKind;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Formats for `symbol_map`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SymbolFormat {
+    /// `BB:AAAA kind NAME` per line.
+    Text,
+    /// An array of `{"name", "bank", "address", "kind"}` objects.
+    Json,
+    /// VICE label commands as written by `ld65 -Ln`: `al 00AAAA .NAME`.
+    Vice,
+}
+impl FromStr for SymbolFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "txt" | "text" => Ok(SymbolFormat::Text),
+            "json" => Ok(SymbolFormat::Json),
+            "vice" => Ok(SymbolFormat::Vice),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Every symbol with its bank, address and kind, sorted by address.
+pub fn symbol_map(symbols: &[Symbol], format: SymbolFormat) -> String {
+    let mut sorted: Vec<&Symbol> = symbols.iter().collect();
+    sorted.sort_by(|a, b| (a.bank, a.address, &a.name).cmp(&(b.bank, b.address, &b.name)));
+    match format {
+        SymbolFormat::Text => sorted
+            .iter()
+            .map(|s| {
+                format!(
+                    "{:02X}:{:04X} {:8} {}\n",
+                    s.bank,
+                    s.address,
+                    s.kind.as_str(),
+                    s.name
+                )
+            })
+            .collect(),
+        SymbolFormat::Json => {
+            let entries: Vec<String> = sorted
+                .iter()
+                .map(|s| {
+                    format!(
+                        "  {{\"name\": {}, \"bank\": {}, \"address\": {}, \"kind\": \"{}\"}}",
+                        json_string(&s.name),
+                        s.bank,
+                        s.address,
+                        s.kind.as_str()
+                    )
+                })
+                .collect();
+            if entries.is_empty() {
+                "[]\n".to_string()
+            } else {
+                format!("[\n{}\n]\n", entries.join(",\n"))
+            }
+        }
+        SymbolFormat::Vice => sorted
+            .iter()
+            .map(|s| format!("al {:06X} .{}\n", s.address, s.name))
+            .collect(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// FCEUX name list files: one per PRG bank with labels in it, plus one for
+/// RAM variables ($0000-$7FFF). Returns `(suffix, contents)` pairs; FCEUX looks for
+/// `<rom>.nes.<suffix>`, e.g. `game.nes.0.nl`, `game.nes.A.nl` (banks are
+/// numbered in hex) and `game.nes.ram.nl`. Constants are left out, since
+/// they are not addresses.
+pub fn fceux_nl(symbols: &[Symbol]) -> Vec<(String, String)> {
+    let mut files: BTreeMap<Option<usize>, String> = BTreeMap::new();
+    let mut sorted: Vec<&Symbol> = symbols.iter().collect();
+    sorted.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+    for symbol in sorted {
+        if symbol.kind == SymbolKind::Constant {
+            continue;
+        }
+        let file = match symbol.prg_offset {
+            Some(offset) => Some(offset / NesHeader::PRG_BANK_SIZE),
+            None if symbol.kind == SymbolKind::Variable && symbol.address < 0x8000 => None,
+            // A label in CHR or outside the ROM: nowhere for the debugger to
+            // show it.
+            None => continue,
+        };
+        let text = files.entry(file).or_default();
+        text.push_str(&format!("${:04X}#{}#\n", symbol.address, symbol.name));
+    }
+    files
+        .into_iter()
+        .map(|(file, text)| match file {
+            Some(bank) => (format!("{:X}.nl", bank), text),
+            None => ("ram.nl".to_string(), text),
+        })
+        .collect()
+}
+
+/// A Mesen label file (`.mlb`). PRG ROM labels use `P:` with their offset
+/// into PRG ROM. Variables in internal RAM and its mirrors use `R:`, save RAM
+/// at $6000-$7FFF uses `S:`, and hardware registers use `G:`. Constants are left
+/// out, since they are not addresses. Labels keep the comment from the line
+/// that defines them; characters Mesen does not allow in names, like the `::`
+/// of scoped labels, are written as `_`.
+pub fn mesen_mlb(symbols: &[Symbol]) -> String {
+    let mut labels: Vec<(char, usize, &Symbol)> = symbols
+        .iter()
+        .filter(|symbol| symbol.kind != SymbolKind::Constant)
+        .filter_map(|symbol| {
+            let address = symbol.address as usize;
+            let (kind, offset) = match (symbol.prg_offset, address) {
+                (Some(offset), _) => ('P', offset),
+                // A label in CHR or outside the ROM, which is not in RAM.
+                (None, _) if symbol.kind != SymbolKind::Variable => return None,
+                (None, 0x0000..=0x1FFF) => ('R', address & 0x07FF),
+                (None, 0x2000..=0x401F) => ('G', address),
+                (None, 0x6000..=0x7FFF) => ('S', address - 0x6000),
+                _ => return None,
+            };
+            Some((kind, offset, symbol))
+        })
+        .collect();
+    labels.sort_by(|a, b| (a.0, a.1, &a.2.name).cmp(&(b.0, b.1, &b.2.name)));
+    labels
+        .into_iter()
+        .map(|(kind, offset, symbol)| {
+            let name = mesen_name(&symbol.name);
+            match &symbol.comment {
+                Some(comment) => format!("{}:{:04X}:{}:{}\n", kind, offset, name, comment),
+                None => format!("{}:{:04X}:{}\n", kind, offset, name),
+            }
+        })
+        .collect()
+}
+
+// Mesen only accepts letters, digits, `_` and `@` in label names, so the
+// `::` and `.` of scoped and local labels become `_`.
+fn mesen_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '@' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -1,9 +1,130 @@
+use crate::common::Loc;
+use crate::diagnostic::Diagnostic;
 use crate::insts::RamAddress;
 use std::collections::HashMap;
 
+/// What a symbol names.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// Code or data in ROM.
+    Label,
+    /// A plain number.
+    Constant,
+    /// A location in RAM.
+    Variable,
+}
+impl SymbolKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Constant => "constant",
+            SymbolKind::Variable => "variable",
+        }
+    }
+}
+
+// The value of a symbol: an address in a bank, or a plain number.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Value {
+    Address(RamAddress),
+    Number(i32),
+}
+impl Value {
+    // The value as it is used in expressions.
+    pub fn number(&self) -> i32 {
+        match self {
+            Value::Address(address) => i32::from(address.address),
+            Value::Number(n) => *n,
+        }
+    }
+    // The value as a word, with negative numbers in two's complement, as
+    // symbol files show it.
+    pub fn word(&self) -> u16 {
+        self.number() as u16
+    }
+    // The bank of an address; numbers are in bank 0.
+    pub fn bank(&self) -> u8 {
+        match self {
+            Value::Address(address) => address.bank,
+            Value::Number(_) => 0,
+        }
+    }
+    pub fn address(&self) -> Option<&RamAddress> {
+        match self {
+            Value::Address(address) => Some(address),
+            Value::Number(_) => None,
+        }
+    }
+}
+
+// Whether `name` is a local label, `.name` or `@name`, which belongs to the
+// global label above it.
+pub fn is_local(name: &str) -> bool {
+    name.starts_with('.') || name.starts_with('@')
+}
+
+// Whether `name` defines an anonymous label: `:`, or only `+` or only `-`
+// characters.
+pub fn is_anonymous(name: &str) -> bool {
+    name == ":"
+        || (!name.is_empty() && (name.chars().all(|c| c == '+') || name.chars().all(|c| c == '-')))
+}
+
+// The hidden name of the `n`th anonymous label of `kind` (`:`, `+`, `--`...).
+pub fn anonymous_name(kind: &str, n: usize) -> String {
+    format!("{}#{}", kind, n)
+}
+
+// The kind of anonymous label a name from `anonymous_name` belongs to.
+pub fn anonymous_kind(name: &str) -> Option<&str> {
+    name.rsplit_once('#')
+        .map(|(kind, _)| kind)
+        .filter(|kind| is_anonymous(kind))
+}
+
+// A symbol definition: what it names and where it was written.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Label {
+    pub kind: SymbolKind,
+    pub value: Value,
+    pub loc: Loc,
+    // Comment on the line that defines the label.
+    pub comment: Option<String>,
+    // Defined with `.set`, so it can be defined again with `.set`.
+    pub redefinable: bool,
+    // How many expressions use the symbol.
+    pub references: usize,
+    // Written to symbol files and debug info.
+    pub exported: bool,
+    // Only visible in the scope that defines it.
+    pub local: bool,
+}
+impl Label {
+    pub fn new(kind: SymbolKind, value: Value, loc: Loc) -> Label {
+        Label {
+            kind,
+            value,
+            loc,
+            comment: None,
+            redefinable: false,
+            references: 0,
+            exported: true,
+            local: false,
+        }
+    }
+    pub fn with_comment(mut self, comment: Option<String>) -> Label {
+        self.comment = comment;
+        self
+    }
+    pub fn redefinable(mut self) -> Label {
+        self.redefinable = true;
+        self
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SymbolTable {
-    table: HashMap<String, RamAddress>,
+    table: HashMap<String, Label>,
 }
 impl SymbolTable {
     pub fn new() -> SymbolTable {
@@ -11,13 +132,77 @@ impl SymbolTable {
             table: HashMap::new(),
         }
     }
-    pub fn insert(&mut self, key: String, val: RamAddress) -> Option<RamAddress> {
-        self.table.insert(key, val)
+    // Adds a definition of `key`. A name can only be defined once, unless
+    // every definition of it uses `.set`.
+    pub fn define(&mut self, key: String, val: Label) -> Result<(), Diagnostic> {
+        if let Some(previous) = self.table.get(&key) {
+            if !(val.redefinable && previous.redefinable) {
+                let mut e = Diagnostic::error(format!("`{}` is already defined", key))
+                    .with_loc(val.loc.clone());
+                if previous.loc.is_known() {
+                    e = e.with_related(
+                        Diagnostic::note("previously defined here").with_loc(previous.loc.clone()),
+                    );
+                } else {
+                    e = e.with_related(Diagnostic::note("previously defined on the command line"));
+                }
+                if val.redefinable || previous.redefinable {
+                    e = e.with_note("only symbols defined with `.set` can be redefined");
+                }
+                return Err(e);
+            }
+        }
+        self.table.insert(key, val);
+        Ok(())
+    }
+    // The name `name` refers to when it is used in `scope`, like `a::b`: the
+    // innermost of `a::b::name`, `a::name` and `name` that is defined.
+    pub fn resolve(&self, name: &str, scope: &str) -> Option<String> {
+        let mut scope = scope;
+        loop {
+            let key = if scope.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}::{}", scope, name)
+            };
+            if self.table.contains_key(&key) {
+                return Some(key);
+            }
+            if scope.is_empty() {
+                return None;
+            }
+            scope = scope.rfind("::").map_or("", |i| &scope[..i]);
+        }
+    }
+    // The definition `name` refers to in `scope`.
+    pub fn lookup(&self, name: &str, scope: &str) -> Option<&Label> {
+        self.resolve(name, scope)
+            .and_then(|key| self.table.get(&key))
+    }
+    // Counts a use of `name` in `scope`, if it is defined.
+    pub fn reference(&mut self, name: &str, scope: &str) {
+        if let Some(key) = self.resolve(name, scope) {
+            if let Some(label) = self.table.get_mut(&key) {
+                label.references += 1;
+            }
+        }
     }
-    pub fn get(&self, key: &String) -> Option<&RamAddress> {
+    // Whether both tables define the same names with the same values.
+    pub fn same_values(&self, other: &SymbolTable) -> bool {
+        self.table.len() == other.table.len()
+            && self.table.iter().all(|(key, label)| {
+                other
+                    .table
+                    .get(key)
+                    .is_some_and(|other| other.value == label.value)
+            })
+    }
+    pub fn get(&self, key: &String) -> Option<&Label> {
         self.table.get(key)
     }
-    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Label)> {
+        self.table.iter()
+    }
     pub fn contains(&self, key: &String) -> bool {
         self.table.contains_key(key)
     }
@@ -1,34 +1,45 @@
-use crate::common::{Annot, Loc};
+use crate::common::{Annot, FileId, Loc};
+use crate::diagnostic::Diagnostic;
+use crate::directive::Directive;
 use log::debug;
+use std::str::FromStr;
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LexErrorKind {
-    #[allow(dead_code)]
     InvalidChar(char),
-    #[allow(dead_code)]
+    // End of line inside a string literal.
     Eof,
+    InvalidNumber,
 }
-#[allow(dead_code)]
-type LexError = Annot<LexErrorKind>;
+pub type LexError = Annot<LexErrorKind>;
 
 impl LexError {
-    #[allow(dead_code)]
     fn invalid_char(c: char, loc: Loc) -> Self {
         LexError::new(LexErrorKind::InvalidChar(c), loc)
     }
-    #[allow(dead_code)]
     fn eof(loc: Loc) -> Self {
         LexError::new(LexErrorKind::Eof, loc)
     }
+    fn invalid_number(loc: Loc) -> Self {
+        LexError::new(LexErrorKind::InvalidNumber, loc)
+    }
 }
 
-#[derive(PartialOrd, PartialEq, Debug)]
+impl From<LexError> for Diagnostic {
+    fn from(e: LexError) -> Diagnostic {
+        let message = match e.value {
+            LexErrorKind::InvalidChar(c) => format!("invalid character `{}`", c),
+            LexErrorKind::Eof => "unterminated string".to_string(),
+            LexErrorKind::InvalidNumber => "invalid number".to_string(),
+        };
+        Diagnostic::error(message).with_loc(e.loc)
+    }
+}
+
+#[derive(PartialOrd, PartialEq, Debug, Clone)]
 pub enum TokenKind {
     Opcode(Vec<char>),
-    Adr8(u8),
-    Adr16(u16),
     U8(u8),
     U16(u16),
-    Im(u8),
     LabelDef(Vec<char>),
     Label(Vec<char>),
     Comment(Vec<char>),
@@ -37,20 +48,37 @@ pub enum TokenKind {
     X,
     Y,
     A,
-    #[allow(dead_code)]
+    // `z:` before an operand or `.b`/`.z` after an opcode: zero page.
+    ForceZp,
+    // `a:` before an operand or `.w`/`.a` after an opcode: absolute.
+    ForceAbs,
     Comma,
     LParen,
     RParen,
     #[allow(dead_code)]
     Spaces,
-    Arrow,
+    Hash,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
 }
 
 pub type Token = Annot<TokenKind>;
 impl Token {
-    fn im(n: u8, loc: Loc) -> Self {
-        Self::new(TokenKind::Im(n), loc)
-    }
     fn label(label: Vec<char>, loc: Loc) -> Self {
         Self::new(TokenKind::Label(label), loc)
     }
@@ -64,7 +92,6 @@ impl Token {
     fn comment(comment: Vec<char>, loc: Loc) -> Self {
         Self::new(TokenKind::Comment(comment), loc)
     }
-    #[allow(dead_code)]
     fn comma(loc: Loc) -> Self {
         Self::new(TokenKind::Comma, loc)
     }
@@ -74,12 +101,6 @@ impl Token {
     fn string(string: Vec<char>, loc: Loc) -> Self {
         Self::new(TokenKind::String(string), loc)
     }
-    fn adr8(adr: u8, loc: Loc) -> Self {
-        Self::new(TokenKind::Adr8(adr), loc)
-    }
-    fn adr16(adr: u16, loc: Loc) -> Self {
-        Self::new(TokenKind::Adr16(adr), loc)
-    }
     fn u8(adr: u8, loc: Loc) -> Self {
         Self::new(TokenKind::U8(adr), loc)
     }
@@ -95,6 +116,13 @@ impl Token {
     fn a(loc: Loc) -> Self {
         Self::new(TokenKind::A, loc)
     }
+    // A forced operand size from the letter of a `z:` prefix or `.b` suffix.
+    fn size(letter: char, loc: Loc) -> Self {
+        match letter.to_ascii_lowercase() {
+            'z' | 'b' => Self::new(TokenKind::ForceZp, loc),
+            _ => Self::new(TokenKind::ForceAbs, loc),
+        }
+    }
     fn lparen(loc: Loc) -> Self {
         Self::new(TokenKind::LParen, loc)
     }
@@ -105,8 +133,16 @@ impl Token {
     fn spaces(loc: Loc) -> Self {
         Self::new(TokenKind::Spaces, loc)
     }
-    fn arrow(loc: Loc) -> Self {
-        Self::new(TokenKind::Arrow, loc)
+    fn operator(kind: TokenKind, loc: Loc) -> Self {
+        Self::new(kind, loc)
+    }
+    // Whether this token can end an operand, so that a following `-`, `*`
+    // or `%` is a binary operator rather than a prefix.
+    fn ends_value(&self) -> bool {
+        matches!(
+            self.value,
+            TokenKind::U8(_) | TokenKind::U16(_) | TokenKind::Label(_) | TokenKind::RParen
+        )
     }
 }
 
@@ -124,53 +160,151 @@ impl Token {
 //     }
 // }
 
-pub fn tokenize(line: impl Into<String>) -> Vec<Token> {
+// Whether `name`, written at the start of a line, defines a local label
+// rather than being a directive.
+fn is_local_label(name: &[char]) -> bool {
+    name.len() > 1 && Directive::from_str(&name.iter().collect::<String>()).is_err()
+}
+
+// Tokenizes line `line_no` (1-based) of `file`.
+pub fn tokenize(
+    line: impl Into<String>,
+    file: FileId,
+    line_no: usize,
+) -> Result<Vec<Token>, LexError> {
     let buf: &Vec<char> = &line.into().chars().collect();
+    let loc = |start: usize, end: usize| Loc::new(file, line_no, start, end);
     let mut pos = 0;
     let mut tokens: Vec<Token> = Vec::new();
     debug!("{:?}", buf);
     let mut has_op = false;
     while pos < buf.len() {
         let mut cur = pos;
-        let mut head_ch = buf[cur];
+        let head_ch = buf[cur];
+        let next_ch = buf.get(cur + 1).cloned();
+        let after_value = tokens.last().is_some_and(|t| t.ends_value());
         // separator
         if head_ch == ',' {
-            // skip comma token
-            //tokens.push(Token::comma(Loc(pos, pos+1)));
-            pos = pos + 1;
+            tokens.push(Token::comma(loc(pos, pos + 1)));
+            pos += 1;
             continue;
         }
-        if head_ch == '(' || head_ch == ')' || head_ch == '<' {
+        if head_ch == '(' || head_ch == ')' {
             tokens.push(match head_ch {
-                '(' => Token::lparen(Loc(pos, pos + 1)),
-                ')' => Token::rparen(Loc(pos, pos + 1)),
-                '<' => Token::arrow(Loc(pos, pos + 1)),
-                _ => panic!(),
+                '(' => Token::lparen(loc(pos, pos + 1)),
+                ')' => Token::rparen(loc(pos, pos + 1)),
+                _ => unreachable!(),
             });
-            pos = pos + 1;
+            pos += 1;
+            continue;
+        }
+        // anonymous labels: `-`, `++` or `:` at the start of a line defines
+        // one, and `-`, `++`, `:-` or `:++` as an operand refers to one
+        if matches!(head_ch, '+' | '-' | ':') && !after_value {
+            let run_start = if head_ch == ':' { cur + 1 } else { cur };
+            let mut end = run_start;
+            while end < buf.len() && matches!(buf[end], '+' | '-') && buf[end] == buf[run_start] {
+                end += 1;
+            }
+            let ends_word = buf
+                .get(end)
+                .is_none_or(|c| matches!(c, ' ' | '\t' | ',' | ')' | ';'));
+            if ends_word && pos == 0 && (head_ch != ':' || end == run_start) {
+                tokens.push(Token::label_def(buf[pos..end].to_vec(), loc(pos, end)));
+                pos = end;
+                continue;
+            }
+            if ends_word && has_op && end > run_start {
+                tokens.push(Token::label(buf[pos..end].to_vec(), loc(pos, end)));
+                pos = end;
+                continue;
+            }
+        }
+        // two character operators
+        let op = match (head_ch, next_ch) {
+            ('<', Some('<')) => Some(TokenKind::Shl),
+            ('>', Some('>')) => Some(TokenKind::Shr),
+            ('<', Some('=')) => Some(TokenKind::Le),
+            ('>', Some('=')) => Some(TokenKind::Ge),
+            ('<', Some('>')) | ('!', Some('=')) => Some(TokenKind::Ne),
+            ('=', Some('=')) => Some(TokenKind::Eq),
+            _ => None,
+        };
+        if let Some(op) = op {
+            tokens.push(Token::operator(op, loc(pos, pos + 2)));
+            pos += 2;
+            continue;
+        }
+        // single character operators
+        // `%` followed by a binary digit in operand position is a binary number.
+        let is_binary_number = head_ch == '%' && !after_value && matches!(next_ch, Some('0'..='1'));
+        let op = match head_ch {
+            '#' => Some(TokenKind::Hash),
+            '+' => Some(TokenKind::Plus),
+            '-' => Some(TokenKind::Minus),
+            '*' => Some(TokenKind::Star),
+            '/' => Some(TokenKind::Slash),
+            '%' if !is_binary_number => Some(TokenKind::Percent),
+            '&' => Some(TokenKind::Ampersand),
+            '|' => Some(TokenKind::Pipe),
+            '^' => Some(TokenKind::Caret),
+            '~' => Some(TokenKind::Tilde),
+            '<' => Some(TokenKind::Lt),
+            '>' => Some(TokenKind::Gt),
+            '=' => Some(TokenKind::Eq),
+            _ => None,
+        };
+        if let Some(op) = op {
+            tokens.push(Token::operator(op, loc(pos, pos + 1)));
+            // What follows `NAME =` is a value, not an instruction.
+            if head_ch == '=' {
+                has_op = true;
+            }
+            pos += 1;
             continue;
         }
         // comment
         if head_ch == ';' {
-            //tokens.push(Token::comment(buf[pos..buf.len()].to_vec(), Loc(pos, buf.len())));
+            // The text after the `;`.
+            tokens.push(Token::comment(buf[pos + 1..].to_vec(), loc(pos, buf.len())));
             break;
         }
         // variables
-        if head_ch.is_ascii_alphabetic() || buf[cur] == '.' {
+        let is_cheap_local =
+            head_ch == '@' && next_ch.is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+        if head_ch.is_ascii_alphabetic() || head_ch == '.' || is_cheap_local {
             let is_head = cur == 0;
+            // `z:` or `a:` in front of an operand, but not a scope like `a::b`.
+            if has_op
+                && matches!(head_ch.to_ascii_lowercase(), 'z' | 'a')
+                && next_ch == Some(':')
+                && buf.get(pos + 2) != Some(&':')
+            {
+                tokens.push(Token::size(head_ch, loc(pos, pos + 2)));
+                pos += 2;
+                continue;
+            }
+            cur += 1;
             while cur < buf.len()
-                && (buf[cur].is_ascii_alphabetic() || buf[cur].is_ascii_digit() || buf[cur] == ':' || buf[cur] == '.' || buf[cur] == '_')
+                && (buf[cur].is_ascii_alphabetic()
+                    || buf[cur].is_ascii_digit()
+                    || buf[cur] == ':'
+                    || buf[cur] == '.'
+                    || buf[cur] == '_')
             {
                 cur += 1;
             }
             debug!("{:?}, {:?}, {:?}, ", head_ch, cur, pos);
             if buf[cur - 1] == ':' {
                 // Label definition (ends with colon)
-                tokens.push(Token::label_def(buf[pos..cur].to_vec(), Loc(pos, cur)));
-            } else if buf[pos] == '.' {
+                tokens.push(Token::label_def(buf[pos..cur].to_vec(), loc(pos, cur)));
+            } else if buf[pos] == '.' && has_op {
+                // Local label used as an operand
+                tokens.push(Token::label(buf[pos..cur].to_vec(), loc(pos, cur)));
+            } else if buf[pos] == '.' && !(is_head && is_local_label(&buf[pos..cur])) {
                 // Directive (starts with dot)
                 has_op = true;
-                tokens.push(Token::directive(buf[pos..cur].to_vec(), Loc(pos, cur)));
+                tokens.push(Token::directive(buf[pos..cur].to_vec(), loc(pos, cur)));
             } else if cur - pos == 1
                 && (buf[cur - 1] == 'X'
                     || buf[cur - 1] == 'x'
@@ -181,21 +315,31 @@ pub fn tokenize(line: impl Into<String>) -> Vec<Token> {
             {
                 // Single character registers
                 tokens.push(match buf[cur - 1] {
-                    'X' | 'x' => Token::x(Loc(pos, cur)),
-                    'Y' | 'y' => Token::y(Loc(pos, cur)),
-                    'A' | 'a' => Token::a(Loc(pos, cur)),
-                    _ => panic!(""),
+                    'X' | 'x' => Token::x(loc(pos, cur)),
+                    'Y' | 'y' => Token::y(loc(pos, cur)),
+                    'A' | 'a' => Token::a(loc(pos, cur)),
+                    _ => unreachable!(),
                 });
             } else if is_head && !has_op {
                 // Label definition at start of line (no colon)
-                tokens.push(Token::label_def(buf[pos..cur].to_vec(), Loc(pos, cur)));
+                tokens.push(Token::label_def(buf[pos..cur].to_vec(), loc(pos, cur)));
             } else if has_op {
                 // After opcode/directive, treat as label/operand
-                tokens.push(Token::label(buf[pos..cur].to_vec(), Loc(pos, cur)));
+                tokens.push(Token::label(buf[pos..cur].to_vec(), loc(pos, cur)));
             } else {
-                // Default to opcode
+                // Default to opcode, with an optional `.b`/`.w` (or `.z`/`.a`)
+                // size suffix
                 has_op = true;
-                tokens.push(Token::opcode(buf[pos..cur].to_vec(), Loc(pos, cur)));
+                let len = cur - pos;
+                if len > 2
+                    && buf[cur - 2] == '.'
+                    && matches!(buf[cur - 1].to_ascii_lowercase(), 'b' | 'w' | 'z' | 'a')
+                {
+                    tokens.push(Token::opcode(buf[pos..cur - 2].to_vec(), loc(pos, cur - 2)));
+                    tokens.push(Token::size(buf[cur - 1], loc(cur - 2, cur)));
+                } else {
+                    tokens.push(Token::opcode(buf[pos..cur].to_vec(), loc(pos, cur)));
+                }
             }
             pos = cur;
             continue;
@@ -206,7 +350,7 @@ pub fn tokenize(line: impl Into<String>) -> Vec<Token> {
                 cur += 1;
             }
             // skip spaces token
-            // tokens.push(Token::spaces(Loc(pos, cur)));
+            // tokens.push(Token::spaces(loc(pos, cur)));
             pos = cur;
             continue;
         }
@@ -216,21 +360,17 @@ pub fn tokenize(line: impl Into<String>) -> Vec<Token> {
             while cur < buf.len() && buf[cur] != '"' {
                 cur += 1;
             }
-            if buf[cur] == '"' {
+            if cur < buf.len() {
                 cur += 1;
             } else {
-                panic!("\" not found");
+                return Err(LexError::eof(loc(pos, cur)));
             }
-            tokens.push(Token::string(buf[pos + 1..cur - 1].to_vec(), Loc(pos, cur)));
+            tokens.push(Token::string(buf[pos + 1..cur - 1].to_vec(), loc(pos, cur)));
             pos = cur;
             continue;
         }
 
         // number
-        if head_ch == '#' {
-            cur += 1;
-            head_ch = buf[cur];
-        }
         let mut start_pos = cur;
         let radix = match head_ch {
             '$' => {
@@ -244,40 +384,29 @@ pub fn tokenize(line: impl Into<String>) -> Vec<Token> {
                 2
             }
             '0'..='9' => 10,
-            _ => panic!(),
+            _ => return Err(LexError::invalid_char(head_ch, loc(pos, pos + 1))),
         };
         while cur < buf.len() && buf[cur].is_digit(radix) {
             cur += 1;
         }
-        let str: String = buf[start_pos..cur].into_iter().collect();
-        tokens.push(if buf[pos] == '#' {
-            Token::im(u8::from_str_radix(&str, radix).unwrap(), Loc(pos, cur))
-        } else {
-            let val = u16::from_str_radix(&str, radix).unwrap();
-            // todo
-            // 多分下記条件曖昧
-            // u16で256未満の場合等
-            // ディレクティブの種類で判別
-            if (radix == 2 && cur - start_pos == 8)
-                || (radix == 16 && cur - start_pos == 2)
-                || (radix == 10 && val <= 256)
+        let str: String = buf[start_pos..cur].iter().collect();
+        let val = u16::from_str_radix(&str, radix)
+            .map_err(|_| LexError::invalid_number(loc(pos, cur)))?;
+        // Literals written with at most two hex digits (or eight binary
+        // digits, or a decimal value below 256) are 8-bit, which selects the
+        // zero page forms of instructions.
+        let digits = cur - start_pos;
+        tokens.push(
+            if (radix == 2 && digits <= 8)
+                || (radix == 16 && digits <= 2)
+                || (radix == 10 && val <= 0xFF)
             {
-                if let TokenKind::Directive(_) = &tokens.last().unwrap().value {
-                    Token::u8(u8::from_str_radix(&str, radix).unwrap(), Loc(pos, cur))
-                } else {
-                    Token::adr8(u8::from_str_radix(&str, radix).unwrap(), Loc(pos, cur))
-                }
+                Token::u8(val as u8, loc(pos, cur))
             } else {
-                if let TokenKind::Directive(_) = &tokens.last().unwrap().value {
-                    Token::u16(val, Loc(pos, cur))
-                } else {
-                    Token::adr16(val, Loc(pos, cur))
-                }
-            }
-            //Token::adr(u8::from_str_radix(&str,radix).unwrap(), Loc(pos, cur))
-        });
+                Token::u16(val, loc(pos, cur))
+            },
+        );
         pos = cur;
-        continue;
     }
-    tokens
+    Ok(tokens)
 }
@@ -0,0 +1,73 @@
+mod common;
+
+#[cfg(test)]
+mod anonymous_tests {
+    use crate::common::{assemble, assemble_errors};
+
+    #[test]
+    fn test_plus_minus() {
+        let output = assemble(
+            r#"
+-   DEX
+    BNE -
+    BEQ +
+    BCC ++
+    NOP
++   NOP
+++  NOP
+--  BNE --
+    JMP -
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..32],
+            &[
+                0xCA, 0xD0, 0xFD, 0xF0, 0x03, 0x90, 0x02, 0xEA, 0xEA, 0xEA, 0xD0, 0xFE, 0x4C, 0x00,
+                0xC0, 0xFF
+            ]
+        );
+        // Anonymous labels are not listed as symbols.
+        assert!(output.symbols.is_empty());
+    }
+
+    #[test]
+    fn test_colon() {
+        let output = assemble(
+            r#"
+:   LDA #1
+    BNE :-
+    BEQ :+
+    BCC :++
+:   NOP
+:   JMP :--
+    .dw :-
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..30],
+            &[0xA9, 0x01, 0xD0, 0xFC, 0xF0, 0x02, 0x90, 0x01, 0xEA, 0x4C, 0x08, 0xC0, 0x09, 0xC0]
+        );
+    }
+
+    #[test]
+    fn test_operators_still_work() {
+        let output = assemble("    LDA #1 - 1\n    LDA #-1\n    .db 2-1, -2, 1 + 1\n");
+        assert_eq!(
+            &output.rom[16..23],
+            &[0xA9, 0x00, 0xA9, 0xFF, 0x01, 0xFE, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble_errors("    BNE -\n    BNE :--\n:   NOP\n    BEQ +\n    BEQ :+\n"),
+            vec![
+                "no anonymous label `-` precedes `-`",
+                "no anonymous label `:` precedes `:--`",
+                "no anonymous label `+` follows",
+                "no anonymous label `:` follows",
+            ]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+#[cfg(test)]
+mod api_tests {
+    use famiasm::symbol_file::{symbol_map, SymbolFormat};
+    use famiasm::{assemble_source, MemoryLoader, Options, SourceLoader, Symbol, SymbolKind};
+    use std::io;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    // Serves a fixed file instead of reading the disk.
+    struct FakeLoader;
+    impl SourceLoader for FakeLoader {
+        fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+            if path == Path::new("data/tiles.chr") {
+                Ok(vec![0xDE, 0xAD])
+            } else {
+                Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
+            }
+        }
+    }
+
+    #[test]
+    fn test_assemble_source() {
+        let source = r#"
+    .inesprg 1
+    .ineschr 0
+    .inesmir 1
+    .inesmap 0
+
+    .bank 0
+    .org $C000
+START:
+    LDA #$42
+    .incbin "tiles.chr"
+LOOP:
+    JMP LOOP
+"#;
+        let options = Options {
+            file_name: "data/main.asm".to_string(),
+            loader: Box::new(FakeLoader),
+            ..Default::default()
+        };
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(output.rom.len(), 16 + 16384);
+        assert_eq!(
+            &output.rom[16..23],
+            &[0xA9, 0x42, 0xDE, 0xAD, 0x4C, 0x04, 0xC0]
+        );
+        assert_eq!(
+            output.symbols,
+            vec![
+                Symbol {
+                    name: "LOOP".to_string(),
+                    kind: SymbolKind::Label,
+                    bank: 0,
+                    address: 0xC004,
+                    prg_offset: Some(0x0004),
+                    comment: None,
+                    references: 1,
+                    local: false,
+                },
+                Symbol {
+                    name: "START".to_string(),
+                    kind: SymbolKind::Label,
+                    bank: 0,
+                    address: 0xC000,
+                    prg_offset: Some(0x0000),
+                    comment: None,
+                    references: 0,
+                    local: false,
+                },
+            ]
+        );
+        assert!(output.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_assemble_source_errors() {
+        let options = Options {
+            loader: Box::new(FakeLoader),
+            ..Default::default()
+        };
+        let errors = assemble_source("    LDA missing\n    .incbin \"x.bin\"\n", &options)
+            .expect_err("Expected errors");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "undefined symbol `missing`");
+        assert_eq!(errors[0].file.as_deref(), Some("<input>"));
+        assert!(errors[1].message.starts_with("cannot read `x.bin`"));
+    }
+
+    #[test]
+    fn test_memory_loader_search_paths() {
+        let mut loader = MemoryLoader::new();
+        loader.insert("src/local.bin", vec![0x01]);
+        loader.insert("lib/shared.bin", vec![0x02]);
+        loader.insert("gfx/tiles.chr", vec![0x03]);
+        let options = Options {
+            file_name: "src/main.asm".to_string(),
+            search_paths: vec![PathBuf::from("lib")],
+            loader: Box::new(loader),
+            ..Default::default()
+        };
+        let source = r#"
+    .inesprg 1
+    .bank 0
+    .org $C000
+    .incbin "local.bin"
+    .incbin "shared.bin"
+    .incbin "../gfx/tiles.chr"
+"#;
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(&output.rom[16..19], &[0x01, 0x02, 0x03]);
+
+        let errors = assemble_source("    .incbin \"missing.bin\"\n", &options)
+            .expect_err("Expected errors");
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0]
+                .message
+                .starts_with("cannot read `src/missing.bin`"),
+            "{}",
+            errors[0]
+        );
+        assert_eq!(
+            errors[0].note.as_deref(),
+            Some("also searched lib/missing.bin")
+        );
+    }
+
+    #[test]
+    fn test_end_of_address_space() {
+        let mut loader = MemoryLoader::new();
+        loader.insert("vectors.bin", vec![0x00, 0x00, 0x00, 0xC0, 0x00, 0x00]);
+        loader.insert("huge.bin", vec![0; 0x10000]);
+        let options = Options {
+            loader: Box::new(loader),
+            ..Default::default()
+        };
+        let source =
+            "    .inesprg 1\n    .bank 0\n    .org $FFF9\n    NOP\n    .incbin \"vectors.bin\"\n";
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(
+            &output.rom[16 + 0x3FF9..16 + 0x4000],
+            &[0xEA, 0x00, 0x00, 0x00, 0xC0, 0x00, 0x00]
+        );
+
+        let source = "    .inesprg 1\n    .bank 0\n    .org $C000\n    .incbin \"huge.bin\"\n";
+        let errors = assemble_source(source, &options).expect_err("Expected errors");
+        assert_eq!(
+            errors[0].message,
+            "`huge.bin` is 65536 bytes, more than the 64 KiB address space"
+        );
+    }
+
+    #[test]
+    fn test_defines() {
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), 1), ("PPU".to_string(), 0x2000)],
+            ..Default::default()
+        };
+        let source = "    .inesprg 1\n    .bank 0\n    .org $C000\n    LDA #DEBUG\n    STA PPU+1\n";
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(&output.rom[16..21], &[0xA9, 0x01, 0x8D, 0x01, 0x20]);
+    }
+
+    #[test]
+    fn test_listing() {
+        let options = Options {
+            file_name: "game.asm".to_string(),
+            listing: true,
+            ..Default::default()
+        };
+        let source = "    .inesprg 1\n    .bank 0\n    .org $C000\nSTART: LDA #$42\n\n    .db 1, 2, 3, 4, 5, 6, 7, 8, 9\n    JMP START\n";
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        let expected = "\
+#[1]   game.asm
+    1                                        .inesprg 1
+    2                                        .bank 0
+    3                                        .org $C000
+    4  00:C000  A9 42                    START: LDA #$42
+    5
+    6  00:C002  01 02 03 04 05 06 07 08      .db 1, 2, 3, 4, 5, 6, 7, 8, 9
+       00:C00A  09
+    7  00:C00B  4C 00 C0                     JMP START
+";
+        assert_eq!(output.listing.as_deref(), Some(expected));
+
+        let output = assemble_source(source, &Options::default()).unwrap();
+        assert_eq!(output.listing, None);
+    }
+
+    #[test]
+    fn test_symbol_references() {
+        let options = Options {
+            defines: vec![("SPEED".to_string(), 2)],
+            ..Default::default()
+        };
+        let source = r#"
+    .inesprg 1
+    .bank 0
+    .org $C000
+STEP .set 1
+START:
+    LDA #SPEED
+    ADC #STEP
+    .dw START, START+SPEED
+UNUSED:
+    .org START+4
+"#;
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        let references: Vec<(&str, usize)> = output
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.references))
+            .collect();
+        assert_eq!(
+            references,
+            vec![("SPEED", 2), ("START", 3), ("STEP", 1), ("UNUSED", 0)]
+        );
+
+        let errors = assemble_source("SPEED = 1\n", &options).unwrap_err();
+        assert_eq!(errors[0].message, "`SPEED` is already defined");
+        assert_eq!(
+            errors[0].related[0].message,
+            "previously defined on the command line"
+        );
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let options = Options {
+            file_name: "game.asm".to_string(),
+            debug_info: true,
+            defines: vec![("VALUE".to_string(), 5)],
+            ..Default::default()
+        };
+        let source = "    .inesprg 1\n    .bank 0\n    .org $C000\nSTART: LDA #$42\n    .db 1, 2\nLOOP: JMP LOOP\n";
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        let expected = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=1,lib=0,line=3,mod=1,scope=1,seg=1,span=3,sym=3,type=0
+file\tid=0,name=\"game.asm\",size=86,mtime=0x00000000,mod=0
+line\tid=0,file=0,line=4,span=0
+line\tid=1,file=0,line=5,span=1
+line\tid=2,file=0,line=6,span=2
+mod\tid=0,name=\"game.asm\",file=0
+scope\tid=0,name=\"\",mod=0,size=7,span=0+1+2
+seg\tid=0,name=\"PRG0_C000\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=4,size=3
+sym\tid=0,name=\"LOOP\",addrsize=absolute,scope=0,def=2,val=0xC004,size=3,seg=0,type=lab
+sym\tid=1,name=\"START\",addrsize=absolute,scope=0,def=0,val=0xC000,size=4,seg=0,type=lab
+sym\tid=2,name=\"VALUE\",addrsize=zeropage,scope=0,val=0x5,type=equ
+";
+        assert_eq!(output.debug_info.as_deref(), Some(expected));
+
+        let output = assemble_source(source, &Options::default()).unwrap();
+        assert_eq!(output.debug_info, None);
+    }
+
+    #[test]
+    fn test_fceux_name_lists() {
+        let source = r#"
+    .inesprg 12
+    .ineschr 1
+    .bank 0
+    .org $8000
+RESET:
+    JMP RESET
+    .bank 10
+    .org $8000
+FAR:
+    RTS
+    .bank 11
+    .org $C000
+NMI:
+    RTI
+    .org $0300
+buffer:
+    .org $2000
+PPUCTRL:
+SPEED = 3
+    .bank 12
+    .org $0000
+tiles:
+    .db 0
+"#;
+        // Constants, including those from -D, are not addresses, and labels
+        // in CHR banks are not in RAM.
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), 1)],
+            ..Default::default()
+        };
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(
+            famiasm::symbol_file::fceux_nl(&output.symbols),
+            vec![
+                (
+                    "ram.nl".to_string(),
+                    "$0300#buffer#\n$2000#PPUCTRL#\n".to_string()
+                ),
+                ("0.nl".to_string(), "$8000#RESET#\n".to_string()),
+                ("A.nl".to_string(), "$8000#FAR#\n".to_string()),
+                ("B.nl".to_string(), "$C000#NMI#\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mesen_labels() {
+        let source = r#"
+    .inesprg 2
+    .ineschr 1
+    .bank 0
+    .org $8000
+RESET:          ; entry point
+    JMP RESET
+    .bank 1
+    .org $C000
+NMI: RTI
+    .org $0300
+buffer:
+    .org $6000
+save:           ; battery-backed
+    .org $2000
+PPUCTRL:
+SPEED = 3
+    .bank 2
+    .org $0000
+tiles:
+    .db 0
+"#;
+        // Constants, including those from -D, are not addresses, and labels
+        // in CHR banks are not in RAM.
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), 1)],
+            ..Default::default()
+        };
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(
+            famiasm::symbol_file::mesen_mlb(&output.symbols),
+            "G:2000:PPUCTRL\nP:0000:RESET:entry point\nP:4000:NMI\nR:0300:buffer\nS:0000:save:battery-backed\n"
+        );
+    }
+
+    #[test]
+    fn test_mesen_scoped_names() {
+        let source = r#"
+    .inesprg 1
+    .bank 0
+    .org $C000
+    .proc main
+.loop:
+    JMP .loop
+    .endproc
+"#;
+        let output = assemble_source(source, &Options::default()).expect("Failed to assemble");
+        assert_eq!(
+            famiasm::symbol_file::mesen_mlb(&output.symbols),
+            "P:0000:main\nP:0000:main_loop\n"
+        );
+    }
+
+    #[test]
+    fn test_symbol_map_formats() {
+        let source = r#"
+    .inesprg 1
+    .ineschr 1
+    .bank 0
+    .org $C000
+RESET:
+    JMP RESET
+    .org $0300
+buffer:
+"#;
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), 1)],
+            ..Default::default()
+        };
+        let symbols = assemble_source(source, &options)
+            .expect("Failed to assemble")
+            .symbols;
+        assert_eq!(
+            symbol_map(&symbols, SymbolFormat::Text),
+            "00:0001 constant DEBUG\n00:0300 variable buffer\n00:C000 label    RESET\n"
+        );
+        assert_eq!(
+            symbol_map(&symbols, SymbolFormat::Json),
+            r#"[
+  {"name": "DEBUG", "bank": 0, "address": 1, "kind": "constant"},
+  {"name": "buffer", "bank": 0, "address": 768, "kind": "variable"},
+  {"name": "RESET", "bank": 0, "address": 49152, "kind": "label"}
+]
+"#
+        );
+        assert_eq!(
+            symbol_map(&symbols, SymbolFormat::Vice),
+            "al 000001 .DEBUG\nal 000300 .buffer\nal 00C000 .RESET\n"
+        );
+        assert_eq!(symbol_map(&[], SymbolFormat::Json), "[]\n");
+    }
+}
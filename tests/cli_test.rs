@@ -0,0 +1,218 @@
+#[cfg(test)]
+mod cli_tests {
+    use std::fs;
+    use std::process::{Command, Output};
+
+    const MINIMAL_ASM: &str = r#"
+    .inesprg 1
+    .ineschr 0
+    .inesmir 1
+    .inesmap 0
+
+    .bank 0
+    .org $C000
+START:
+    JMP START
+    .org $FFFA
+VECTORS:
+    .dw 0
+    .dw START
+    .dw 0
+"#;
+
+    fn famiasm(args: &[&str]) -> Output {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .output()
+            .expect("Failed to run famiasm")
+    }
+
+    #[test]
+    fn test_output_path() {
+        fs::create_dir_all("test_cli_out.asm.d").unwrap();
+        fs::write("test_cli_out.asm.d/game.asm", MINIMAL_ASM).unwrap();
+
+        let output = famiasm(&["test_cli_out.asm.d/game.asm"]);
+        assert!(output.status.success());
+        let rom = fs::read("test_cli_out.asm.d/game.nes").expect("Missing default output");
+        assert_eq!(rom.len(), 16 + 16384);
+
+        let output = famiasm(&[
+            "-o",
+            "test_cli_out.asm.d/custom.rom",
+            "test_cli_out.asm.d/game.asm",
+        ]);
+        assert!(output.status.success());
+        assert!(fs::metadata("test_cli_out.asm.d/custom.rom").is_ok());
+
+        fs::remove_dir_all("test_cli_out.asm.d").ok();
+    }
+
+    #[test]
+    fn test_raw_format_and_symbols() {
+        fs::write("test_cli_raw.asm", MINIMAL_ASM).unwrap();
+
+        let output = famiasm(&[
+            "--format",
+            "raw",
+            "--symbols=test_cli_raw.sym",
+            "test_cli_raw.asm",
+        ]);
+        let rom = fs::read("test_cli_raw.bin");
+        let symbols = fs::read_to_string("test_cli_raw.sym");
+        fs::remove_file("test_cli_raw.asm").ok();
+        fs::remove_file("test_cli_raw.bin").ok();
+        fs::remove_file("test_cli_raw.sym").ok();
+
+        assert!(output.status.success());
+        let rom = rom.expect("Missing raw output");
+        assert_eq!(rom.len(), 16384);
+        assert_eq!(&rom[0..3], &[0x4C, 0x00, 0xC0]);
+        assert_eq!(
+            symbols.unwrap(),
+            "00:C000 label    START\n00:FFFA label    VECTORS\n"
+        );
+    }
+
+    #[test]
+    fn test_include_dir() {
+        fs::create_dir_all("test_cli_include/assets").unwrap();
+        fs::write("test_cli_include/assets/data.bin", [0x12, 0x34]).unwrap();
+        fs::write(
+            "test_cli_include/main.asm",
+            "    .inesprg 1\n    .ineschr 0\n    .bank 0\n    .org $C000\n    .incbin \"data.bin\"\n",
+        )
+        .unwrap();
+
+        let output = famiasm(&["-I", "test_cli_include/assets", "test_cli_include/main.asm"]);
+        let rom = fs::read("test_cli_include/main.nes");
+        fs::remove_dir_all("test_cli_include").ok();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(&rom.unwrap()[16..18], &[0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_help_and_version() {
+        let output = famiasm(&["--help"]);
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Usage: famiasm"));
+
+        let output = famiasm(&["--version"]);
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("famiasm "));
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let output = famiasm(&["--no-such-option", "x.asm"]);
+        assert_eq!(output.status.code(), Some(2));
+        let output = famiasm(&[]);
+        assert_eq!(output.status.code(), Some(2));
+        let output = famiasm(&["--format", "zip", "x.asm"]);
+        assert_eq!(output.status.code(), Some(2));
+        let output = famiasm(&["--symbol-format", "yaml", "x.asm"]);
+        assert_eq!(output.status.code(), Some(2));
+
+        let output = famiasm(&["test_cli_does_not_exist.asm"]);
+        assert_eq!(output.status.code(), Some(3));
+
+        fs::write("test_cli_error.asm", "    LDA missing\n").unwrap();
+        let output = famiasm(&["test_cli_error.asm"]);
+        fs::remove_file("test_cli_error.asm").ok();
+        fs::remove_file("test_cli_error.nes").ok();
+        assert_eq!(output.status.code(), Some(1));
+    }
+
+    #[test]
+    fn test_verbosity() {
+        fs::write("test_cli_verbose.asm", MINIMAL_ASM).unwrap();
+        let verbose = famiasm(&["-v", "test_cli_verbose.asm"]);
+        let quiet = famiasm(&["-q", "test_cli_verbose.asm"]);
+        fs::remove_file("test_cli_verbose.asm").ok();
+        fs::remove_file("test_cli_verbose.nes").ok();
+
+        assert!(String::from_utf8_lossy(&verbose.stderr).contains("info: wrote 16400 bytes"));
+        assert!(quiet.stderr.is_empty());
+    }
+
+    #[test]
+    fn test_defines() {
+        fs::write(
+            "test_cli_defines.asm",
+            "    .inesprg 1\n    .ineschr 0\n    .bank 0\n    .org $C000\n    LDA #LEVEL\n    .db DEBUG, LEVEL+1\n    .dw ADDR\n",
+        )
+        .unwrap();
+        let output = famiasm(&[
+            "-D",
+            "DEBUG",
+            "-DLEVEL=$10",
+            "--define=ADDR=0x1234",
+            "test_cli_defines.asm",
+        ]);
+        let rom = fs::read("test_cli_defines.nes");
+        let bad = famiasm(&["-D", "1X=2", "test_cli_defines.asm"]);
+        fs::remove_file("test_cli_defines.asm").ok();
+        fs::remove_file("test_cli_defines.nes").ok();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(&rom.unwrap()[16..22], &[0xA9, 0x10, 0x01, 0x11, 0x34, 0x12]);
+        assert_eq!(bad.status.code(), Some(2));
+    }
+
+    #[test]
+    fn test_listing_file() {
+        fs::write("test_cli_listing.asm", MINIMAL_ASM).unwrap();
+        let output = famiasm(&["--listing", "test_cli_listing.lst", "test_cli_listing.asm"]);
+        let listing = fs::read_to_string("test_cli_listing.lst");
+        fs::remove_file("test_cli_listing.asm").ok();
+        fs::remove_file("test_cli_listing.nes").ok();
+        fs::remove_file("test_cli_listing.lst").ok();
+
+        assert!(output.status.success());
+        let listing = listing.expect("Missing listing");
+        assert!(listing.starts_with("#[1]   test_cli_listing.asm\n"));
+        assert!(listing.contains("   10  00:C000  4C 00 C0                     JMP START\n"));
+    }
+
+    #[test]
+    fn test_debug_info_file() {
+        fs::write("test_cli_dbg.asm", MINIMAL_ASM).unwrap();
+        let output = famiasm(&["--dbg", "test_cli_dbg.dbg", "test_cli_dbg.asm"]);
+        let dbg = fs::read_to_string("test_cli_dbg.dbg");
+        fs::remove_file("test_cli_dbg.asm").ok();
+        fs::remove_file("test_cli_dbg.nes").ok();
+        fs::remove_file("test_cli_dbg.dbg").ok();
+
+        assert!(output.status.success());
+        let dbg = dbg.expect("Missing debug info");
+        assert!(dbg.starts_with("version\tmajor=2,minor=0\n"));
+        assert!(dbg.contains("file\tid=0,name=\"test_cli_dbg.asm\","));
+        assert!(dbg.contains("line\tid=1,file=0,line=10,span=0\n"));
+    }
+
+    #[test]
+    fn test_ram_usage_file() {
+        let source = format!("    .rsset $0000\ncounter .rs 2\n{}", MINIMAL_ASM);
+        fs::write("test_cli_ram.asm", source).unwrap();
+        let output = famiasm(&["--ram-usage", "test_cli_ram.txt", "test_cli_ram.asm"]);
+        let usage = fs::read_to_string("test_cli_ram.txt");
+        fs::remove_file("test_cli_ram.asm").ok();
+        fs::remove_file("test_cli_ram.nes").ok();
+        fs::remove_file("test_cli_ram.txt").ok();
+
+        assert!(output.status.success());
+        let usage = usage.expect("Missing RAM usage");
+        assert!(usage.starts_with("zero page $0000-$00FF     2 of   256 bytes used (0%)\n"));
+        assert!(usage.ends_with("$0000     2 counter\n"));
+    }
+}
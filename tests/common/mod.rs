@@ -0,0 +1,41 @@
+// Helpers shared by the integration tests. Each test file uses only some of
+// them.
+#![allow(dead_code)]
+
+use famiasm::{assemble_source, Diagnostic, Options, Output};
+
+/// Selects the only PRG bank and starts it at $C000.
+pub const HEADER: &str = "    .inesprg 1\n    .bank 0\n    .org $C000\n";
+
+/// Assembles `body` after `HEADER` with `options`.
+pub fn assemble_with(body: &str, options: &Options) -> Output {
+    let source = format!("{}{}", HEADER, body);
+    assemble_source(&source, options).unwrap_or_else(|e| panic!("Failed to assemble: {:?}", e))
+}
+
+/// Assembles `body` after `HEADER` with the default options.
+pub fn assemble(body: &str) -> Output {
+    assemble_with(body, &Options::default())
+}
+
+/// Returns the first `len` PRG bytes of `output`.
+pub fn prg(output: &Output, len: usize) -> Vec<u8> {
+    output.rom[16..16 + len].to_vec()
+}
+
+/// Assembles `body` after `HEADER` with `options`, which must fail.
+pub fn errors_with(body: &str, options: &Options) -> Vec<Diagnostic> {
+    let source = format!("{}{}", HEADER, body);
+    match assemble_source(&source, options) {
+        Ok(_) => panic!("Expected errors"),
+        Err(errors) => errors,
+    }
+}
+
+/// Returns the messages of the errors from assembling `body` after `HEADER`.
+pub fn assemble_errors(body: &str) -> Vec<String> {
+    errors_with(body, &Options::default())
+        .iter()
+        .map(|e| e.message.clone())
+        .collect()
+}
@@ -7,7 +7,7 @@ mod tests {
 
     fn compile_with_famiasm(input: &str, output: &str) -> Result<(), String> {
         let status = Command::new("cargo")
-            .args(&["run", "--", input])
+            .args(["run", "--", input])
             .status()
             .map_err(|e| format!("Failed to run famiasm: {}", e))?;
 
@@ -27,7 +27,7 @@ mod tests {
 
     fn compile_with_nesasm(input: &str, output: &str) -> Result<(), String> {
         let status = Command::new("nesasm")
-            .args(&[input, "-o", output])
+            .args([input, "-o", output])
             .status()
             .map_err(|e| format!("Failed to run nesasm: {}", e))?;
 
@@ -211,6 +211,15 @@ START:
     .dw 0
 "#;
         run_comparison_test("data_directives", asm);
+
+        // The comparison is skipped without nesasm, so check that every
+        // `.dw` value is emitted, not only the first of each line.
+        let rom = fs::read("tests/expected/data_directives_famiasm.nes")
+            .expect("Failed to read famiasm output");
+        assert_eq!(
+            &rom[16 + 12..16 + 24],
+            &[0x34, 0x12, 0x78, 0x56, 0xBC, 0x9A, 0xF0, 0xDE, 0x00, 0xC0, 0x18, 0xC0]
+        );
     }
 
     #[test]
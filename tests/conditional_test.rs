@@ -0,0 +1,153 @@
+mod common;
+
+#[cfg(test)]
+mod conditional_tests {
+    use crate::common::{self, assemble_errors, assemble_with};
+    use famiasm::Options;
+
+    // Assembles `body` at $C000 with `defines` and returns the first `len`
+    // PRG bytes.
+    fn assemble(body: &str, defines: &[&str], len: usize) -> Vec<u8> {
+        let options = Options {
+            defines: defines.iter().map(|name| (name.to_string(), 1)).collect(),
+            ..Default::default()
+        };
+        common::prg(&assemble_with(body, &options), len)
+    }
+
+    #[test]
+    fn test_if_else() {
+        let source = r#"
+    .if 2*2 == 4
+    .db 1
+    .else
+    .db 2
+    .endif
+    .if 0
+    .db 3
+    .else
+    .db 4
+    .endif
+    .if 0
+    .db 5
+    .endif
+"#;
+        assert_eq!(assemble(source, &[], 3), vec![1, 4, 0xFF]);
+    }
+
+    #[test]
+    fn test_ifdef() {
+        let source = r#"
+    .ifdef DEBUG
+    .db 1
+    .else
+    .db 2
+    .endif
+    .ifndef DEBUG
+    .db 3
+    .endif
+"#;
+        assert_eq!(assemble(source, &[], 2), vec![2, 3]);
+        assert_eq!(assemble(source, &["DEBUG"], 2), vec![1, 0xFF]);
+    }
+
+    #[test]
+    fn test_nested() {
+        let source = r#"
+    .ifdef PAL
+    .if 1
+    .db 1
+    .else
+    .db 2
+    .endif
+    .else
+    .ifdef DEBUG
+    .db 3
+    .else
+    .db 4
+    .endif
+    .endif
+"#;
+        assert_eq!(assemble(source, &[], 1), vec![4]);
+        assert_eq!(assemble(source, &["DEBUG"], 1), vec![3]);
+        assert_eq!(assemble(source, &["PAL", "DEBUG"], 1), vec![1]);
+    }
+
+    #[test]
+    fn test_skipped_lines_are_not_assembled() {
+        // Neither the undefined symbols nor the unknown instruction in the
+        // skipped blocks are errors.
+        let source = r#"
+    .if 0
+    .if missing
+    .endif
+    BOGUS
+    .else
+    .db 1
+    .endif
+"#;
+        assert_eq!(assemble(source, &[], 1), vec![1]);
+    }
+
+    #[test]
+    fn test_labels_and_macros() {
+        let source = r#"
+args .macro
+    .if \# > 1
+    .db \2
+    .else
+    .db \1
+    .endif
+    .endm
+    args 1
+    args 1, 2
+skip: .if 0
+after:
+    .endif
+    .dw skip
+"#;
+        assert_eq!(assemble(source, &[], 4), vec![1, 2, 0x02, 0xC0]);
+    }
+
+    #[test]
+    fn test_recursive_macro() {
+        let source = r#"
+pushall .macro
+    .if \# > 0
+    .db \1
+    pushall \2, \3
+    .endif
+    .endm
+    pushall 1, 2, 3
+    .ifdef PAL
+region .macro
+    .db 50
+    .endm
+    .else
+region .macro
+    .db 60
+    .endm
+    .endif
+    region
+"#;
+        assert_eq!(assemble(source, &[], 4), vec![1, 2, 3, 60]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = assemble_errors(
+            "    .endif\n    .if\n    .else\n    .else\n    .endif 1\n    .ifdef 1\n    .endif\n    .ifndef FOO\n",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "`.endif` without a matching `.if`",
+                "`.if` requires an argument",
+                "`.else` after `.else`",
+                "`.endif` takes no arguments",
+                "`.ifdef` expects a symbol name",
+                "`.ifndef` has no matching `.endif`",
+            ]
+        );
+    }
+}
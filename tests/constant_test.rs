@@ -0,0 +1,115 @@
+mod common;
+
+#[cfg(test)]
+mod constant_tests {
+    use crate::common::{assemble, assemble_errors};
+    use famiasm::SymbolKind;
+
+    #[test]
+    fn test_constants() {
+        let source = r#"
+PPUCTRL = $2000
+PPUMASK .equ PPUCTRL+1
+    SPEED = 3
+START:
+    STA PPUCTRL
+    STA PPUMASK
+    LDA #SPEED
+    .dw LATER
+LATER = $1234
+"#;
+        let output = assemble(source);
+        // The definitions take no space: START is at $C000.
+        assert_eq!(
+            &output.rom[16..26],
+            &[0x8D, 0x00, 0x20, 0x8D, 0x01, 0x20, 0xA9, 0x03, 0x34, 0x12]
+        );
+        let kinds: Vec<(&str, SymbolKind, u16)> = output
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind, s.address))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("LATER", SymbolKind::Constant, 0x1234),
+                ("PPUCTRL", SymbolKind::Constant, 0x2000),
+                ("PPUMASK", SymbolKind::Constant, 0x2001),
+                ("SPEED", SymbolKind::Constant, 3),
+                ("START", SymbolKind::Label, 0xC000),
+            ]
+        );
+        assert!(output
+            .symbols
+            .iter()
+            .all(|s| s.kind == SymbolKind::Label || s.prg_offset.is_none()));
+    }
+
+    #[test]
+    fn test_set() {
+        let source = r#"
+COUNT .set 1
+    LDA #COUNT
+COUNT .set COUNT+1
+    LDA #COUNT
+    .db COUNT*2
+"#;
+        let output = assemble(source);
+        assert_eq!(&output.rom[16..21], &[0xA9, 0x01, 0xA9, 0x02, 0x04]);
+
+        // Above its first definition, a `.set` symbol has no value yet.
+        assert_eq!(
+            assemble_errors("    LDA #F\n    .db F\nF .set 1\nF .set 2\n"),
+            vec![
+                "`F` is used before it is defined with `.set`",
+                "`F` is used before it is defined with `.set`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_negative() {
+        let source = r#"
+OFF = -2
+START:
+    LDA START+2+OFF
+    LDA #OFF
+    .db OFF
+    .dw OFF
+"#;
+        let output = assemble(source);
+        assert_eq!(
+            &output.rom[16..24],
+            &[0xAD, 0x00, 0xC0, 0xA9, 0xFE, 0xFE, 0xFE, 0xFF]
+        );
+        // Symbol files show negative constants as words.
+        let off = output.symbols.iter().find(|s| s.name == "OFF").unwrap();
+        assert_eq!((off.kind, off.address), (SymbolKind::Constant, 0xFFFE));
+    }
+
+    #[test]
+    fn test_redefinition() {
+        assert_eq!(
+            assemble_errors("A1 = 1\nA1 = 2\nB .set 1\nB .equ 2\nC .equ 1\nC .set 2\nL:\nL = 1\n"),
+            vec![
+                "`A1` is already defined",
+                "`B` is already defined",
+                "`C` is already defined",
+                "`L` is already defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble_errors("EMPTY =\n    .equ 1\nBIG = $FFFF+1\nSMALL = -32769\n"),
+            vec![
+                "`EMPTY` requires a value",
+                "`.equ` must follow the name it defines",
+                "value 65536 does not fit in a word",
+                "value -32769 does not fit in a word",
+            ]
+        );
+    }
+}
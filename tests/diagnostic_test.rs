@@ -0,0 +1,228 @@
+#[cfg(test)]
+mod diagnostic_tests {
+    use std::fs;
+    use std::process::{Command, Output};
+
+    fn run_famiasm(input_file: &str, source: &str) -> Output {
+        run_famiasm_with_args(input_file, source, &[])
+    }
+
+    fn run_famiasm_with_args(input_file: &str, source: &str, args: &[&str]) -> Output {
+        fs::write(input_file, source).expect("Failed to write test file");
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .arg(input_file)
+            .output()
+            .expect("Failed to run famiasm");
+        fs::remove_file(input_file).ok();
+        fs::remove_file(input_file.replace(".asm", ".nes")).ok();
+        output
+    }
+
+    #[test]
+    fn test_undefined_symbol() {
+        let output = run_famiasm(
+            "test_diag_undefined.asm",
+            "    .org $C000\nSTART:\n    LDA missing+1\n    JMP START\n",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: undefined symbol `missing`"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("--> test_diag_undefined.asm:3:9"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("3 |     LDA missing+1"), "{}", stderr);
+        assert!(stderr.contains("  |         ^^^^^^^\n"), "{}", stderr);
+    }
+
+    #[test]
+    fn test_invalid_addressing_mode() {
+        let output = run_famiasm(
+            "test_diag_addressing.asm",
+            "    .org $C000\n    LDX $10,X\n",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: `LDX` does not support"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("test_diag_addressing.asm:2:5"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_unknown_instruction() {
+        let output = run_famiasm("test_diag_unknown.asm", "    .org $C000\n    FOO #1\n");
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: unknown instruction `FOO`"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_lex_error() {
+        let output = run_famiasm("test_diag_lex.asm", "    .org $C000\n    LDA #@\n");
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: invalid character `@`"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("test_diag_lex.asm:2:10"), "{}", stderr);
+    }
+
+    #[test]
+    fn test_branch_out_of_range() {
+        let output = run_famiasm(
+            "test_diag_branch.asm",
+            "    .org $C000\nSTART:\n    .org $C100\n    BNE START\n",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: branch target is out of range"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("= note:"), "{}", stderr);
+    }
+
+    const MANY_ERRORS: &str = "    .org $C000\nSTART:\n    FOO\n    LDX $10,X\n    LDA missing\n    .db 300\n    JMP START\n";
+
+    #[test]
+    fn test_multiple_errors() {
+        let output = run_famiasm("test_diag_multiple.asm", MANY_ERRORS);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines = ["3:5", "4:5", "5:9", "6:9"];
+        let mut last = 0;
+        for line in lines.iter() {
+            let needle = format!("test_diag_multiple.asm:{}", line);
+            let pos = stderr.find(&needle).unwrap_or_else(|| panic!("{}", stderr));
+            assert!(pos > last, "errors out of order: {}", stderr);
+            last = pos;
+        }
+        assert!(
+            stderr.contains("error: aborting due to 4 previous errors"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let output = run_famiasm_with_args(
+            "test_diag_max_errors.asm",
+            MANY_ERRORS,
+            &["--max-errors", "2"],
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("-->").count(), 2, "{}", stderr);
+        assert!(
+            stderr.contains("error: aborting after 2 errors"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_max_errors_keeps_first_errors() {
+        // The undefined symbol is only found after every line is parsed, but
+        // it is still the first error shown.
+        let output = run_famiasm_with_args(
+            "test_diag_max_errors_order.asm",
+            "    .org $C000\n    LDA u1\n    BOGUS\n    .bogus\n    .org\n",
+            &["--max-errors", "3"],
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("--> test_diag_max_errors_order.asm:2:9"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("--> test_diag_max_errors_order.asm:4:5"),
+            "{}",
+            stderr
+        );
+        assert!(
+            !stderr.contains("--> test_diag_max_errors_order.asm:5:"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_error_after_parsing_points_at_statement() {
+        let output = run_famiasm(
+            "test_diag_not_in_rom.asm",
+            "    .org $6000\n    NOP\ndata: .db 1, 2\n",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: address $6000 is not in ROM"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("test_diag_not_in_rom.asm:2:5"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("test_diag_not_in_rom.asm:3:7"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("  |       ^^^^^^^^\n"), "{}", stderr);
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let output = run_famiasm(
+            "test_diag_duplicate.asm",
+            "    .org $C000\nloop:\n    NOP\nloop: JMP loop\n",
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("error: `loop` is already defined"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("--> test_diag_duplicate.asm:4:1"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("note: previously defined here"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("--> test_diag_duplicate.asm:2:1"),
+            "{}",
+            stderr
+        );
+    }
+}
@@ -0,0 +1,237 @@
+#[cfg(test)]
+mod expression_tests {
+    use std::fs;
+    use std::process::Command;
+
+    fn run_famiasm(input_file: &str) -> Result<Vec<u8>, String> {
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--", input_file])
+            .output()
+            .map_err(|e| format!("Failed to run famiasm: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "famiasm failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        // Read the output file
+        let output_file = input_file.replace(".asm", ".nes");
+        fs::read(&output_file).map_err(|e| format!("Failed to read output file: {}", e))
+    }
+
+    #[test]
+    fn test_operand_expressions() {
+        let test_asm = r#"
+    .inesprg 1
+    .ineschr 0
+    .inesmir 1
+    .inesmap 0
+
+    .bank 0
+    .org $C000
+
+START:
+    LDA table+1         ; AD 12 C0
+    LDA #(3*2)          ; A9 06
+    STA $2000|$10       ; 8D 10 20
+    LDA #-1             ; A9 FF
+    LDX #(1<<4)|(~$F0&$0F) ; A2 1F
+    LDA $10+1,X         ; B5 11
+    JMP START
+
+table:
+    .db 1+2, 10-3, 7%4
+    .dw table-2, START+$100
+
+    .org $FFFA
+    .dw 0
+    .dw START
+    .dw 0
+"#;
+        let test_file = "test_expressions.asm";
+        fs::write(test_file, test_asm).expect("Failed to write test file");
+
+        let result = run_famiasm(test_file);
+        fs::remove_file(test_file).ok();
+        fs::remove_file("test_expressions.nes").ok();
+        assert!(result.is_ok(), "Failed to assemble expressions");
+
+        let output = result.unwrap();
+        assert_eq!(
+            &output[16..30],
+            &[
+                0xAD, 0x12, 0xC0, // LDA table+1
+                0xA9, 0x06, // LDA #(3*2)
+                0x8D, 0x10, 0x20, // STA $2000|$10
+                0xA9, 0xFF, // LDA #-1
+                0xA2, 0x1F, // LDX #(1<<4)|(~$F0&$0F)
+                0xB5, 0x11, // LDA $10+1,X
+            ]
+        );
+        // .db / .dw at table ($C011)
+        assert_eq!(&output[33..40], &[0x03, 0x07, 0x03, 0x0F, 0xC0, 0x00, 0xC1]);
+    }
+
+    #[test]
+    fn test_operand_size_by_value() {
+        let test_asm = r#"
+    .inesprg 1
+    .ineschr 0
+    .inesmir 1
+    .inesmap 0
+
+    .bank 0
+    .org $C000
+
+START:
+    LDA $FF+1           ; AD 00 01
+    LDA 200+100         ; AD 2C 01
+    LDA $80*4           ; AD 00 02
+    LDA 2*$80,X         ; BD 00 01
+    LDA $80+$7F         ; A5 FF
+    JMP START
+"#;
+        let test_file = "test_operand_size.asm";
+        fs::write(test_file, test_asm).expect("Failed to write test file");
+
+        let result = run_famiasm(test_file);
+        fs::remove_file(test_file).ok();
+        fs::remove_file("test_operand_size.nes").ok();
+        assert!(result.is_ok(), "Failed to assemble operands: {:?}", result);
+
+        let output = result.unwrap();
+        assert_eq!(
+            &output[16..30],
+            &[
+                0xAD, 0x00, 0x01, // LDA $FF+1
+                0xAD, 0x2C, 0x01, // LDA 200+100
+                0xAD, 0x00, 0x02, // LDA $80*4
+                0xBD, 0x00, 0x01, // LDA 2*$80,X
+                0xA5, 0xFF, // LDA $80+$7F
+            ]
+        );
+    }
+
+    #[test]
+    fn test_values_out_of_range() {
+        let test_asm = r#"
+    .inesprg 1
+    .bank 0
+    .org $C000
+
+start:
+    JMP -1
+    LDA start+$4000
+    .dw -40000, -32768, $FFFF
+"#;
+        let test_file = "test_out_of_range.asm";
+        fs::write(test_file, test_asm).expect("Failed to write test file");
+
+        let result = run_famiasm(test_file);
+        fs::remove_file(test_file).ok();
+        fs::remove_file("test_out_of_range.nes").ok();
+
+        let stderr = result.expect_err("Values out of range were accepted");
+        assert!(
+            stderr.contains("value -1 does not fit in a word"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("value 65536 does not fit in a word"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("value -40000 does not fit in a word"),
+            "{}",
+            stderr
+        );
+        assert_eq!(
+            stderr.matches("does not fit in a word").count(),
+            3,
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_comparison_expressions() {
+        let test_asm = r#"
+    .inesprg 1
+    .ineschr 0
+    .inesmir 1
+    .inesmap 0
+
+    .bank 0
+    .org $C000
+
+START:
+    .db 2>1, 2<1, 3>=3, 3<=2, 4==4, 4!=4, 4<>5
+    JMP START
+"#;
+        let test_file = "test_comparisons.asm";
+        fs::write(test_file, test_asm).expect("Failed to write test file");
+
+        let result = run_famiasm(test_file);
+        fs::remove_file(test_file).ok();
+        fs::remove_file("test_comparisons.nes").ok();
+        assert!(result.is_ok(), "Failed to assemble comparisons");
+
+        let output = result.unwrap();
+        assert_eq!(&output[16..23], &[1, 0, 1, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_low_high_byte() {
+        let test_asm = r#"
+    .inesprg 1
+    .ineschr 0
+    .inesmir 1
+    .inesmap 0
+
+    .bank 0
+    .org $C000
+
+START:
+    LDA #<table         ; A9 34
+    LDX #>table         ; A2 C0
+    LDY #HIGH(table+$100) ; A0 C1
+    LDA #low(table)     ; A9 34
+    LDA <table          ; A5 34
+    JMP START
+
+    .org $C034
+table:
+    .db <table, >table, LOW(START+1), HIGH(START)
+
+    .org $FFFA
+    .dw 0
+    .dw START
+    .dw 0
+"#;
+        let test_file = "test_low_high.asm";
+        fs::write(test_file, test_asm).expect("Failed to write test file");
+
+        let result = run_famiasm(test_file);
+        fs::remove_file(test_file).ok();
+        fs::remove_file("test_low_high.nes").ok();
+        assert!(result.is_ok(), "Failed to assemble low/high byte operators");
+
+        let output = result.unwrap();
+        assert_eq!(
+            &output[16..29],
+            &[
+                0xA9, 0x34, // LDA #<table
+                0xA2, 0xC0, // LDX #>table
+                0xA0, 0xC1, // LDY #HIGH(table+$100)
+                0xA9, 0x34, // LDA #low(table)
+                0xA5, 0x34, // LDA <table
+                0x4C, 0x00, 0xC0, // JMP START
+            ]
+        );
+        assert_eq!(&output[16 + 0x34..16 + 0x38], &[0x34, 0xC0, 0x01, 0xC0]);
+    }
+}
@@ -5,7 +5,7 @@ mod incbin_tests {
 
     fn run_famiasm(input_file: &str) -> Result<Vec<u8>, String> {
         let output = Command::new("cargo")
-            .args(&["run", "--quiet", "--", input_file])
+            .args(["run", "--quiet", "--", input_file])
             .output()
             .map_err(|e| format!("Failed to run famiasm: {}", e))?;
 
@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod include_tests {
+    use famiasm::{assemble_source, MemoryLoader, Options};
+    use std::path::PathBuf;
+
+    const MAIN: &str =
+        "    .inesprg 1\n    .bank 0\n    .org $C000\n    .include \"lib/macros.asm\"\n";
+
+    fn options(files: &[(&str, &str)]) -> Options {
+        let mut loader = MemoryLoader::new();
+        for (path, text) in files {
+            loader.insert(path, text.as_bytes());
+        }
+        Options {
+            file_name: "src/main.asm".to_string(),
+            search_paths: vec![PathBuf::from("include")],
+            loader: Box::new(loader),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let options = options(&[
+            // Relative to the including file.
+            (
+                "src/lib/macros.asm",
+                "store .macro\n    .db \\1\n    .endm\n    .include \"values.asm\"\n",
+            ),
+            ("src/lib/values.asm", "    .db 1\nVALUES:\n"),
+            // Found in a search path.
+            ("include/shared.asm", "    store 2\n"),
+        ]);
+        let source = format!("{}    .include \"shared.asm\"\n    .dw VALUES\n", MAIN);
+        let output = assemble_source(&source, &options).expect("Failed to assemble");
+        assert_eq!(&output.rom[16..20], &[1, 2, 0x01, 0xC0]);
+    }
+
+    #[test]
+    fn test_listing_lists_each_file() {
+        let mut options = options(&[("src/lib/macros.asm", "    .db 1\n")]);
+        options.listing = true;
+        let output = assemble_source(MAIN, &options).expect("Failed to assemble");
+        let listing = output.listing.unwrap();
+        assert!(listing.contains("#[1]   src/main.asm\n"), "{}", listing);
+        assert!(
+            listing.contains("#[2]   src/lib/macros.asm\n    1  00:C000  01"),
+            "{}",
+            listing
+        );
+    }
+
+    #[test]
+    fn test_conditional_include() {
+        let mut options = options(&[
+            ("src/pal.asm", "REGION = 1\n"),
+            ("src/ntsc.asm", "REGION = 0\n    LDA ptr\n"),
+        ]);
+        options.listing = true;
+        // debug.asm does not exist, and only one of pal.asm and ntsc.asm may
+        // be read. `ptr` is defined further down, so this takes two passes.
+        let source = r#"
+    .inesprg 1
+    .bank 0
+    .org $C000
+    .ifdef DEBUG
+    .include "debug.asm"
+    .endif
+    .ifdef PAL
+    .include "pal.asm"
+    .else
+    .include "ntsc.asm"
+    .endif
+    LDA #REGION
+ptr = $10
+"#;
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(&output.rom[16..20], &[0xA5, 0x10, 0xA9, 0x00]);
+        // Each pass reads ntsc.asm again, but it is listed once.
+        let listing = output.listing.unwrap();
+        assert_eq!(listing.matches("src/ntsc.asm").count(), 1, "{}", listing);
+
+        options.defines = vec![("PAL".to_string(), 1)];
+        let output = assemble_source(source, &options).expect("Failed to assemble");
+        assert_eq!(&output.rom[16..18], &[0xA9, 0x01]);
+    }
+
+    #[test]
+    fn test_error_shows_include_chain() {
+        let options = options(&[
+            ("src/lib/macros.asm", "    .include \"values.asm\"\n"),
+            ("src/lib/values.asm", "    LDA missing\n"),
+        ]);
+        let errors = assemble_source(MAIN, &options).expect_err("Expected errors");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "\
+error: undefined symbol `missing`
+ --> src/lib/values.asm:1:9
+  |
+1 |     LDA missing
+  |         ^^^^^^^
+note: `src/lib/values.asm` is included here
+ --> src/lib/macros.asm:1:14
+  |
+1 |     .include \"values.asm\"
+  |              ^^^^^^^^^^^^
+note: `src/lib/macros.asm` is included here
+ --> src/main.asm:4:14
+  |
+4 |     .include \"lib/macros.asm\"
+  |              ^^^^^^^^^^^^^^^^
+"
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let options = options(&[
+            ("src/lib/macros.asm", "    .include \"../main.asm\"\n"),
+            ("src/main.asm", MAIN),
+        ]);
+        let errors = assemble_source(MAIN, &options).expect_err("Expected errors");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "`src/lib/../main.asm` is included recursively"
+        );
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let options = options(&[]);
+        let errors = assemble_source(MAIN, &options).expect_err("Expected errors");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "cannot read `src/lib/macros.asm`: no such file in memory"
+        );
+        assert_eq!(
+            errors[0].note.as_deref(),
+            Some("also searched include/lib/macros.asm")
+        );
+
+        let errors =
+            assemble_source("    .include missing\n", &options).expect_err("Expected errors");
+        assert_eq!(errors[0].message, "`.include` expects a file name");
+    }
+}
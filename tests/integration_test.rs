@@ -5,7 +5,7 @@ mod integration_tests {
 
     fn ensure_binary_built() {
         let output = Command::new("cargo")
-            .args(&["build"])
+            .args(["build"])
             .output()
             .expect("Failed to build project");
 
@@ -18,7 +18,7 @@ mod integration_tests {
         ensure_binary_built();
 
         let output = Command::new("cargo")
-            .args(&["run", "--quiet", "--", input_file])
+            .args(["run", "--quiet", "--", input_file])
             .output()
             .map_err(|e| format!("Failed to run famiasm: {}", e))?;
 
@@ -0,0 +1,156 @@
+mod common;
+
+#[cfg(test)]
+mod macro_tests {
+    use crate::common::{self, errors_with};
+    use famiasm::Options;
+
+    // Assembles `body` at $C000 and returns the first `len` PRG bytes.
+    fn assemble(body: &str, len: usize) -> Vec<u8> {
+        common::prg(&common::assemble(body), len)
+    }
+
+    // Returns the rendered errors, which name the file.
+    fn assemble_errors(body: &str) -> Vec<String> {
+        let options = Options {
+            file_name: "game.asm".to_string(),
+            ..Default::default()
+        };
+        errors_with(body, &options)
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_arguments() {
+        let source = r#"
+store .macro
+    LDA \1
+    STA \2
+    .endm
+    store #$01, $2000
+.macro count ; the other form
+    .db \#
+    .endm
+    count 1, (2), "a,b"
+    count
+"#;
+        assert_eq!(
+            assemble(source, 7),
+            vec![0xA9, 0x01, 0x8D, 0x00, 0x20, 0x03, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_unique_labels() {
+        let source = r#"
+wait .macro
+loop\@:
+    DEX
+    BNE loop\@
+    .endm
+    wait
+    wait
+"#;
+        assert_eq!(
+            assemble(source, 6),
+            vec![0xCA, 0xD0, 0xFD, 0xCA, 0xD0, 0xFD]
+        );
+    }
+
+    #[test]
+    fn test_nested_macros() {
+        let source = r#"
+inner .macro
+    LDA #\1
+    .endm
+outer .macro
+    inner \2
+    inner \1
+    .endm
+START: outer 1, 2
+    JMP START
+"#;
+        assert_eq!(
+            assemble(source, 7),
+            vec![0xA9, 0x02, 0xA9, 0x01, 0x4C, 0x00, 0xC0]
+        );
+    }
+
+    #[test]
+    fn test_definition_inside_macro() {
+        let source = r#"
+define .macro
+\1 .macro
+    .db \2
+    .endm
+    .endm
+    define five, 5
+    five
+"#;
+        assert_eq!(assemble(source, 1), vec![5]);
+    }
+
+    #[test]
+    fn test_error_points_at_body_and_call() {
+        let errors = assemble_errors("store .macro\n    LDA \\1\n    .endm\n    store missing\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0],
+            "\
+error: undefined symbol `missing`
+ --> game.asm:5:9
+  |
+5 |     LDA \\1
+  |         ^^
+note: in expansion of macro `store`
+ --> game.asm:7:5
+  |
+7 |     store missing
+  |     ^^^^^^^^^^^^^
+"
+        );
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let errors = assemble_errors("forever .macro\n    forever\n    .endm\n    forever\n");
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("error: macro `forever` is nested more than 32 levels deep\n"),
+            "{}",
+            errors[0]
+        );
+
+        // Each level passes different arguments, but the note for the line
+        // that invokes the macro again is only given once.
+        let errors = assemble_errors(
+            "countdown .macro\n    .db \\1\n    countdown \\1-1\n    .endm\n    countdown 40\n",
+        );
+        assert_eq!(
+            errors[0]
+                .matches("in expansion of macro `countdown`")
+                .count(),
+            2,
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn test_definition_errors() {
+        let errors = assemble_errors("    .endm\nopen .macro\n    NOP\n");
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("error: `.endm` without a matching `.macro`\n"));
+        assert!(errors[1].starts_with("error: macro `open` has no matching `.endm`\n"));
+
+        let errors = assemble_errors("twice .macro\n    .endm\ntwice .macro\n    .endm\n");
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].contains("note: first defined here\n"),
+            "{}",
+            errors[0]
+        );
+    }
+}
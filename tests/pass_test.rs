@@ -0,0 +1,185 @@
+mod common;
+
+#[cfg(test)]
+mod pass_tests {
+    use crate::common::{assemble, assemble_errors};
+
+    #[test]
+    fn test_forward_zero_page_symbol() {
+        let output = assemble(
+            r#"
+    LDA ptr
+    STA ptr,X
+    JMP done
+done:
+    RTS
+ptr = $10
+"#,
+        );
+        // `ptr` is defined after its uses but still gets zero page
+        // addressing, which moves `done` two bytes down.
+        assert_eq!(
+            &output.rom[16..24],
+            &[0xA5, 0x10, 0x95, 0x10, 0x4C, 0x07, 0xC0, 0x60]
+        );
+        assert_eq!(output.symbols[0].name, "done");
+        assert_eq!(output.symbols[0].address, 0xC007);
+    }
+
+    #[test]
+    fn test_forward_variable() {
+        let output = assemble(
+            r#"
+    LDA counter
+    LDA buffer
+    LDX counter,Y
+    .rsset $0000
+counter .rs 1
+    .rsset $0300
+buffer .rs 16
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..23],
+            &[0xA5, 0x00, 0xAD, 0x00, 0x03, 0xB6, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_forward_zero_page_only_modes() {
+        let output = assemble(
+            r#"
+    STX var,Y
+    STY ptr,X
+var = $10
+    .zp
+ptr .ds 1
+"#,
+        );
+        // STX has no absolute,Y and STY no absolute,X form, so the guess of
+        // the first pass must not be reported.
+        assert_eq!(&output.rom[16..20], &[0x96, 0x10, 0x94, 0x00]);
+    }
+
+    #[test]
+    fn test_absolute_only_modes() {
+        let output = assemble(
+            r#"
+    LDA ptr,Y
+    JMP (ptr)
+    LDA (ptr),Y
+ptr = $10
+"#,
+        );
+        // LDA has no zero page,Y form and JMP has no zero page form at all.
+        assert_eq!(
+            &output.rom[16..24],
+            &[0xB9, 0x10, 0x00, 0x6C, 0x10, 0x00, 0xB1, 0x10]
+        );
+    }
+
+    #[test]
+    fn test_operand_outside_zero_page() {
+        let errors = assemble_errors("    LDA (200+100),Y\n");
+        assert_eq!(errors, vec!["address $012C is not in zero page"]);
+    }
+
+    #[test]
+    fn test_forced_size() {
+        let output = assemble(
+            r#"
+    LDA.w ptr
+    STA a:ptr,X
+    LDA.b $0010
+    LDA z:$0010,X
+    STA.w $0000,Y
+    ASL a
+    LDA <far
+ptr = $10
+far = $0300
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..33],
+            &[
+                0xAD, 0x10, 0x00, 0x9D, 0x10, 0x00, 0xA5, 0x10, 0xB5, 0x10, 0x99, 0x00, 0x00, 0x0A,
+                0xA5, 0x00, 0xFF
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forced_size_errors() {
+        assert_eq!(
+            assemble_errors("    LDA.b a:$10\n"),
+            vec!["conflicting operand sizes"]
+        );
+        assert_eq!(
+            assemble_errors("    LDA.w #1\n"),
+            vec!["operand size can only be forced for zero page or absolute addressing"]
+        );
+        assert_eq!(
+            assemble_errors("    LDA z:$0300\n"),
+            vec!["address $0300 is not in zero page"]
+        );
+        assert_eq!(
+            assemble_errors("    JMP z:$10\n"),
+            vec!["`JMP` does not support zero page addressing"]
+        );
+    }
+
+    #[test]
+    fn test_promotion() {
+        let output = assemble(
+            r#"
+    LDA $10,Y
+    STA ptr,Y
+    LDX ptr,Y
+    LDA <far,Y
+ptr = $10
+far = $0300
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..27],
+            &[0xB9, 0x10, 0x00, 0x99, 0x10, 0x00, 0xB6, 0x10, 0xB9, 0x00, 0x00]
+        );
+        // Only the zero page symbol that lost its zero page form is reported.
+        let warnings: Vec<&str> = output.warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(
+            warnings,
+            vec!["`STA` does not support zero page,Y addressing; using absolute,Y addressing"]
+        );
+        assert_eq!(output.warnings[0].loc.as_ref().unwrap().line, 6);
+    }
+
+    #[test]
+    fn test_no_convergence() {
+        let errors = assemble_errors(
+            r#"
+    LDA value
+end:
+value = $C102 - end
+"#,
+        );
+        assert_eq!(
+            errors,
+            vec!["instruction sizes did not settle after 16 passes"]
+        );
+
+        // In the last pass `value+1` is guessed to be $0100, which `STX` has
+        // no form for, but only the sizes not settling is reported.
+        let errors = assemble_errors(
+            r#"
+    LDA value
+end:
+    STX value+1,Y
+value = $C102 - end
+"#,
+        );
+        assert_eq!(
+            errors,
+            vec!["instruction sizes did not settle after 16 passes"]
+        );
+    }
+}
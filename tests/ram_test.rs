@@ -0,0 +1,163 @@
+mod common;
+
+#[cfg(test)]
+mod ram_tests {
+    use crate::common::{assemble, assemble_errors, assemble_with};
+    use famiasm::{Options, SymbolKind};
+
+    #[test]
+    fn test_rs() {
+        let source = r#"
+    .rsset $0010
+player_x .rs 1
+player_y .rs 1
+    .rs 2
+score .rs 2
+    .bank 0
+    .org $C000
+    LDA player_y
+    .dw score
+"#;
+        let output = assemble(source);
+        // Nothing is emitted for the variables, which are in zero page.
+        assert_eq!(&output.rom[16..20], &[0xA5, 0x11, 0x14, 0x00]);
+        let variables: Vec<(&str, SymbolKind, u16)> = output
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind, s.address))
+            .collect();
+        assert_eq!(
+            variables,
+            vec![
+                ("player_x", SymbolKind::Variable, 0x10),
+                ("player_y", SymbolKind::Variable, 0x11),
+                ("score", SymbolKind::Variable, 0x14),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sections() {
+        let source = r#"
+    .zp
+ptr: .ds 2
+temp:
+    .ds 1
+    .bss
+buffer: .ds 32
+flags: .ds 1
+    .zp
+    .org $80
+last: .ds 1
+    .code
+    .bank 0
+    .org $C000
+START:
+    .dw ptr, temp, buffer, flags, last
+"#;
+        let options = Options {
+            ram_usage: true,
+            ..Default::default()
+        };
+        let output = assemble_with(source, &options);
+        assert_eq!(
+            &output.rom[16..26],
+            &[0x00, 0x00, 0x02, 0x00, 0x00, 0x03, 0x20, 0x03, 0x80, 0x00]
+        );
+        let start = output.symbols.iter().find(|s| s.name == "START").unwrap();
+        assert_eq!((start.kind, start.address), (SymbolKind::Label, 0xC000));
+        let buffer = output.symbols.iter().find(|s| s.name == "buffer").unwrap();
+        assert_eq!(buffer.kind, SymbolKind::Variable);
+        assert_eq!(
+            output.ram_usage.as_deref(),
+            Some(
+                "\
+zero page $0000-$00FF     4 of   256 bytes used (1%)
+work RAM  $0300-$07FF    33 of  1280 bytes used (2%)
+
+$0000     2 ptr
+$0002     1 temp
+$0080     1 last
+$0300    32 buffer
+$0320     1 flags
+"
+            )
+        );
+
+        let output = assemble(source);
+        assert_eq!(output.ram_usage, None);
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(
+            assemble_errors(
+                "    .rsset $00F0\nbig .rs 17\n    .bss\n    .org $07F0\nbuf: .ds $10\nmore: .ds 1\n    .org $07FF\nlast: .ds 2\n"
+            ),
+            vec![
+                "reserving 17 bytes at $00F0 overflows zero page",
+                "reserving 1 bytes at $0800 overflows work RAM",
+                "reserving 2 bytes at $07FF overflows work RAM",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full_region() {
+        // Filling zero page exactly is fine, but the next byte would be in
+        // the stack.
+        assert_eq!(
+            assemble_errors("    .zp\na1: .ds $100\nb1: .ds 1\n"),
+            vec!["reserving 1 bytes at $0100 overflows zero page"]
+        );
+        assert_eq!(
+            assemble_errors("    .rsset 0\nfirst .rs 1\nrest .rs $FF\nlast .rs 1\n"),
+            vec!["reserving 1 bytes at $0100 overflows zero page"]
+        );
+        // A counter set outside of zero page and work RAM is not limited.
+        let output = assemble("    .rsset $6000\nsave .rs $100\nmore .rs 1\n");
+        let more = output.symbols.iter().find(|s| s.name == "more").unwrap();
+        assert_eq!(more.address, 0x6100);
+    }
+
+    #[test]
+    fn test_overlap() {
+        // `.rs` and `.zp` both start at $0000.
+        assert_eq!(
+            assemble_errors("a1 .rs 1\n    .zp\nz1: .ds 2\n"),
+            vec!["reserving 2 bytes at $0000 overlaps the 1 bytes reserved at $0000"]
+        );
+        assert_eq!(
+            assemble_errors("    .bss\nbuf: .ds 4\n    .org $0302\nflags: .ds 1\n"),
+            vec!["reserving 1 bytes at $0302 overlaps the 4 bytes reserved at $0300"]
+        );
+        // Moved apart, both are counted.
+        let options = Options {
+            ram_usage: true,
+            ..Default::default()
+        };
+        let output = assemble_with("    .rsset $10\na1 .rs 1\n    .zp\nz1: .ds 2\n", &options);
+        let usage = output.ram_usage.unwrap();
+        assert!(
+            usage.starts_with("zero page $0000-$00FF     3 of   256 bytes used (1%)\n"),
+            "{}",
+            usage
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble_errors(
+                "    .zp\n    LDA #1\n    .db 1\n    .bank 0\n    .code 1\n    .code\n    .ds 1\n"
+            ),
+            vec![
+                "instructions cannot be used in a `.zp` section",
+                "`.db` cannot be used in a `.zp` section",
+                "`.bank` cannot be used in a `.zp` section",
+                "`.code` takes no arguments",
+                "`.ds` can only be used in `.zp` and `.bss` sections",
+            ]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+mod common;
+
+#[cfg(test)]
+mod scope_tests {
+    use crate::common::{assemble, assemble_errors};
+    use famiasm::Output;
+
+    fn symbols(output: &Output) -> Vec<(&str, u16, bool)> {
+        output
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.address, s.local))
+            .collect()
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let output = assemble(
+            r#"
+first:
+.loop:
+    BNE .loop
+    BEQ .done
+.done
+second:
+.loop:
+    BNE .loop
+    .dw first.done
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..26],
+            &[0xD0, 0xFE, 0xF0, 0x00, 0xD0, 0xFE, 0x04, 0xC0, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            symbols(&output),
+            vec![
+                ("first", 0xC000, false),
+                ("first.done", 0xC004, true),
+                ("first.loop", 0xC000, true),
+                ("second", 0xC004, false),
+                ("second.loop", 0xC004, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cheap_local_labels() {
+        let output = assemble(
+            r#"
+first:
+@wait:
+    BNE @wait
+second:
+@wait:
+    BNE @wait
+"#,
+        );
+        assert_eq!(&output.rom[16..20], &[0xD0, 0xFE, 0xD0, 0xFE]);
+        assert_eq!(
+            symbols(&output),
+            vec![
+                ("first", 0xC000, false),
+                ("first@wait", 0xC000, true),
+                ("second", 0xC002, false),
+                ("second@wait", 0xC002, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scopes() {
+        let output = assemble(
+            r#"
+    .proc player
+speed = 2
+update:
+    LDA #speed
+.loop:
+    BNE .loop
+    .scope limits
+    max = 7
+    .endscope
+    LDA #limits::max
+    JMP update
+    .endproc
+    LDA #player::speed
+    JSR player::update
+    .dw player, player::update.loop
+    .ifdef player::speed
+    .db 1
+    .endif
+    .ifdef speed
+    .db 2
+    .endif
+"#,
+        );
+        assert_eq!(
+            &output.rom[16..34],
+            &[
+                0xA9, 0x02, 0xD0, 0xFE, 0xA9, 0x07, 0x4C, 0x00, 0xC0, 0xA9, 0x02, 0x20, 0x00, 0xC0,
+                0x00, 0xC0, 0x02, 0xC0
+            ]
+        );
+        assert_eq!(output.rom[34], 0x01);
+        assert_eq!(output.rom[35], 0xFF);
+        assert_eq!(
+            symbols(&output),
+            vec![
+                ("player", 0xC000, false),
+                ("player::limits::max", 7, false),
+                ("player::speed", 2, false),
+                ("player::update", 0xC000, false),
+                ("player::update.loop", 0xC002, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outer_symbols_are_visible() {
+        let output = assemble(
+            r#"
+value = 1
+    .proc outer
+    .proc inner
+    LDA #value
+    LDA #other
+    .endproc
+other = 3
+    .endproc
+other = 4
+"#,
+        );
+        assert_eq!(&output.rom[16..20], &[0xA9, 0x01, 0xA9, 0x03]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble_errors(
+                "main:\n    BNE .nowhere\n    .endproc\n    .scope b\n    .endproc\n    .proc\nfoo::bar: NOP\n    .proc open\n"
+            ),
+            vec![
+                "undefined symbol `main.nowhere`",
+                "`.endproc` without a matching `.proc`",
+                "`.endproc` closes `.scope`",
+                "`.proc` expects a name",
+                "`foo::bar` cannot be defined from outside its scope",
+                "`.proc` has no matching `.endproc`",
+            ]
+        );
+    }
+}